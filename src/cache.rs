@@ -0,0 +1,511 @@
+use std::fs::{self,File};
+use std::io::{self,BufWriter,Read,Seek,SeekFrom,Write};
+use std::mem;
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use memmap2::Mmap;
+
+use csr::{Buffer,CsrVectors};
+use dataset::{Dataset,DatasetError,DatasetErrorKind,ReadOptions,ReadSummary,read_dataset_with_options};
+use nearest::DatasetIndex;
+use postings::{CsrPostings,PostingIndex};
+
+// The binary layout (version 2) consists of the following little-endian fields:
+//   [0..8)   magic "RSTKDSET"
+//   [8..12)  version (u32)
+//   [12..16) reserved (u32)
+//   [16..24) source file size (u64)
+//   [24..32) source file modification time in nanoseconds since UNIX epoch (u64)
+//   [32..40) source read option flags (u64)
+//   [40..48) number of skipped lines in the source (u64)
+//   [48..56) nentries (u64)
+//   [56..64) nfeatures (u64)
+//   [64..72) nlabels (u64)
+//   [72..80) nnz of X (u64)
+//   [80..88) nnz of Y (u64)
+//   [88..96) FNV-1a checksum of the whole file except this field (u64)
+// Then, the following sections follow, each of which is padded to 8 bytes:
+//   the offsets of X ((nentries+1) u64s), the data of X (nnz (u32, f32)s),
+//   the offsets of Y ((nentries+1) u64s), and the data of Y (nnz u32s).
+// The source fields are zero if the file is not a cache of any text file.
+const MAGIC: &[u8; 8] = b"RSTKDSET";
+const VERSION: u32 = 2;
+const HEADER_SIZE: usize = 96;
+const CHECKSUM_AT: usize = 88;
+
+/// SourceStamp identifies the text file and the options from which a cache was built.
+#[derive(Clone,Copy,Debug,Default,Eq,PartialEq)]
+struct SourceStamp {
+    size: u64,
+    mtime: u64,
+    flags: u64,
+}
+
+impl SourceStamp {
+    fn new(filename: &Path, opts: &ReadOptions) -> io::Result<SourceStamp> {
+        let metadata = fs::metadata(filename)?;
+        let mtime = match metadata.modified()?.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs()*1_000_000_000 + d.subsec_nanos() as u64,
+            Err(_) => 0,
+        };
+        let dialect = &opts.dialect;
+        let flags = (opts.skip_bad_lines as u64) | (opts.headerless as u64) << 1 | (opts.strict_header as u64) << 2 |
+            (dialect.tolerant as u64) << 3 | (dialect.comments as u64) << 4 | (dialect.skip_qid as u64) << 5 | (dialect.feature_base as u64) << 32;
+        Ok(SourceStamp{ size: metadata.len(), mtime, flags })
+    }
+}
+
+fn bad_binary(msg: &str) -> DatasetError {
+    DatasetError::new(0, 0, DatasetErrorKind::BadBinary(msg.to_string()))
+}
+
+fn io_error(e: io::Error) -> DatasetError {
+    DatasetError::new(0, 0, DatasetErrorKind::Io(e))
+}
+
+fn padding(size: usize) -> usize {
+    (8 - size%8)%8
+}
+
+// The mapped sections are reinterpreted as the native types, so the host must have the same layout.
+fn is_mappable_platform() -> bool {
+    let x: (u32, f32) = (0, 0.0);
+    let base = &x as *const (u32, f32) as usize;
+    cfg!(target_endian = "little") && mem::size_of::<usize>() == 8 && mem::size_of::<(u32, f32)>() == 8 &&
+        &x.0 as *const u32 as usize == base && &x.1 as *const f32 as usize == base + 4
+}
+
+fn write_sections<W: Write>(w: &mut W, ds: &Dataset) -> io::Result<()> {
+    for &offset in ds.X.offsets() {
+        w.write_all(&(offset as u64).to_le_bytes())?;
+    }
+    for &(key, value) in ds.X.data() {
+        w.write_all(&key.to_le_bytes())?;
+        w.write_all(&value.to_bits().to_le_bytes())?;
+    }
+    for &offset in ds.Y.offsets() {
+        w.write_all(&(offset as u64).to_le_bytes())?;
+    }
+    for &label in ds.Y.data() {
+        w.write_all(&label.to_le_bytes())?;
+    }
+    w.write_all(&[0u8; 8][..padding(4*ds.Y.nnz())])
+}
+
+// write_binary writes the dataset into the file, and fills the checksum after writing the sections.
+fn write_binary(f: File, ds: &Dataset, stamp: &SourceStamp, nskipped: usize) -> io::Result<()> {
+    let mut w = ChecksumWriter{ w: BufWriter::new(f), checksum: FNV1A_INIT };
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&0u32.to_le_bytes())?;
+    let fields = [
+        stamp.size, stamp.mtime, stamp.flags, nskipped as u64,
+        ds.size() as u64, ds.nfeatures as u64, ds.nlabels as u64, ds.X.nnz() as u64, ds.Y.nnz() as u64,
+    ];
+    for field in &fields {
+        w.write_all(&field.to_le_bytes())?;
+    }
+    w.w.write_all(&0u64.to_le_bytes())?;
+    write_sections(&mut w, ds)?;
+    let checksum = w.checksum;
+    let mut f = w.w.into_inner().map_err(|e| { e.into_error() })?;
+    f.seek(SeekFrom::Start(CHECKSUM_AT as u64))?;
+    f.write_all(&checksum.to_le_bytes())
+}
+
+// write_file_atomically writes the file through the temporary file renamed at the end, so the interrupted write leaves no truncated file.
+fn write_file_atomically<F: FnOnce(File) -> io::Result<()>>(filename: &Path, write: F) -> io::Result<()> {
+    let mut tmp_filename = filename.as_os_str().to_owned();
+    tmp_filename.push(".tmp");
+    let result = File::create(&tmp_filename).and_then(write).and_then(|_| { fs::rename(&tmp_filename, filename) });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_filename);
+    }
+    result
+}
+
+/// write_dataset_binary writes the dataset in the binary layout loadable with load_dataset_binary.
+pub fn write_dataset_binary<P: AsRef<Path>>(filename: P, ds: &Dataset) -> io::Result<()> {
+    write_file_atomically(filename.as_ref(), |f| { write_binary(f, ds, &SourceStamp::default(), 0) })
+}
+
+fn read_u64(header: &[u8], at: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&header[at..at+8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn read_stamp<P: AsRef<Path>>(filename: P) -> io::Result<(SourceStamp, usize)> {
+    let mut header = [0u8; HEADER_SIZE];
+    File::open(filename)?.read_exact(&mut header)?;
+    if &header[0..8] != MAGIC || header[8..12] != VERSION.to_le_bytes() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a dataset binary"));
+    }
+    let stamp = SourceStamp{ size: read_u64(&header, 16), mtime: read_u64(&header, 24), flags: read_u64(&header, 32) };
+    Ok((stamp, read_u64(&header, 40) as usize))
+}
+
+fn load_binary<P: AsRef<Path>>(filename: P) -> Result<(Dataset, usize), DatasetError> {
+    if !is_mappable_platform() {
+        return Err(bad_binary("unsupported platform"));
+    }
+    let file = File::open(filename).map_err(io_error)?;
+    let mmap = Arc::new(unsafe { Mmap::map(&file) }.map_err(io_error)?);
+    if mmap.len() < HEADER_SIZE || &mmap[0..8] != MAGIC {
+        return Err(bad_binary("bad magic"));
+    }
+    if mmap[8..12] != VERSION.to_le_bytes() {
+        return Err(bad_binary("unsupported version"));
+    }
+    if checksum(&mmap, CHECKSUM_AT) != read_u64(&mmap, CHECKSUM_AT) {
+        return Err(bad_binary("checksum mismatch"));
+    }
+    let nskipped = read_u64(&mmap, 40) as usize;
+    let nentries = read_u64(&mmap, 48) as usize;
+    let nfeatures = read_u64(&mmap, 56) as usize;
+    let nlabels = read_u64(&mmap, 64) as usize;
+    let xnnz = read_u64(&mmap, 72) as usize;
+    let ynnz = read_u64(&mmap, 80) as usize;
+    let truncated = || { bad_binary("truncated") };
+    let mut offset = HEADER_SIZE;
+    let xoffsets = unsafe { Buffer::<usize>::from_mmap(mmap.clone(), offset, nentries.wrapping_add(1)) }.ok_or_else(truncated)?;
+    offset += 8*xoffsets.len();
+    let xdata = unsafe { Buffer::<(u32, f32)>::from_mmap(mmap.clone(), offset, xnnz) }.ok_or_else(truncated)?;
+    offset += 8*xdata.len();
+    let yoffsets = unsafe { Buffer::<usize>::from_mmap(mmap.clone(), offset, nentries.wrapping_add(1)) }.ok_or_else(truncated)?;
+    offset += 8*yoffsets.len();
+    let ydata = unsafe { Buffer::<u32>::from_mmap(mmap.clone(), offset, ynnz) }.ok_or_else(truncated)?;
+    let ds = Dataset{
+        X: CsrVectors::from_parts(xoffsets, xdata).ok_or_else(|| { bad_binary("inconsistent offsets of X") })?,
+        Y: CsrVectors::from_parts(yoffsets, ydata).ok_or_else(|| { bad_binary("inconsistent offsets of Y") })?,
+        nfeatures,
+        nlabels,
+    };
+    Ok((ds, nskipped))
+}
+
+/// load_dataset_binary loads the dataset written by write_dataset_binary, verifying the checksum.
+/// The file is memory-mapped and used without copying, so it must not be modified while the dataset is alive.
+pub fn load_dataset_binary<P: AsRef<Path>>(filename: P) -> Result<Dataset, DatasetError> {
+    load_binary(filename).map(|(ds, _)| { ds })
+}
+
+/// cache_path returns the path of the binary cache of the given text file.
+pub fn cache_path<P: AsRef<Path>>(filename: P) -> PathBuf {
+    let mut path = filename.as_ref().as_os_str().to_owned();
+    path.push(".bin");
+    PathBuf::from(path)
+}
+
+/// read_dataset_cached is read_dataset_with_options using the binary cache at cache_path(filename).
+/// If the cache is missing or stale, this reads the text file and builds the cache.
+/// The standard input "-" is never cached.
+pub fn read_dataset_cached<P: AsRef<Path>>(filename: P, opts: &ReadOptions) -> Result<(Dataset, ReadSummary), DatasetError> {
+    let filename = filename.as_ref();
+    if filename == Path::new("-") {
+        return read_dataset_with_options(filename, opts);
+    }
+    let cache_filename = cache_path(filename);
+    let stamp = SourceStamp::new(filename, opts).map_err(io_error)?;
+    if let Ok((cache_stamp, _)) = read_stamp(&cache_filename) {
+        if cache_stamp == stamp {
+            match load_binary(&cache_filename) {
+                Ok((ds, nskipped)) => {
+                    info!("loaded the cache {:?}", cache_filename);
+                    return Ok((ds, ReadSummary{ nskipped }));
+                },
+                Err(e) => warn!("ignored the broken cache {:?}: {}", cache_filename, e),
+            }
+        } else {
+            info!("ignored the stale cache {:?}", cache_filename);
+        }
+    }
+    let (ds, summary) = read_dataset_with_options(filename, opts)?;
+    match write_file_atomically(&cache_filename, |f| { write_binary(f, &ds, &stamp, summary.nskipped) }) {
+        Ok(_) => info!("built the cache {:?}", cache_filename),
+        Err(e) => warn!("failed to build the cache {:?}: {}", cache_filename, e),
+    }
+    Ok((ds, summary))
+}
+
+// The binary layout of DatasetIndex (version 3) consists of the following little-endian fields:
+//   [0..8)   magic "RSTKINDX"
+//   [8..12)  version (u32)
+//   [12..16) reserved (u32)
+//   [16..24) nentries (u64)
+//   [24..32) number of the feature keys (u64)
+//   [32..40) number of the postings (u64)
+//   [40..48) nnz of the label vectors (u64)
+//   [48..56) FNV-1a checksum of the whole file except this field (u64)
+//   [56..64) number of the deleted entries (u64)
+// Then, the following sections follow, each of which is padded to 8 bytes:
+//   the number of the features of each entry (nentries u32s), the sorted feature keys (u32s),
+//   the offsets of the posting lists of the keys ((nkeys+1) u64s), the postings ((u32, f32)s),
+//   the offsets of the label vectors ((nentries+1) u64s), the data of the label vectors (nnz u32s),
+//   and the sorted ids of the deleted entries (u32s).
+const INDEX_MAGIC: &[u8; 8] = b"RSTKINDX";
+const INDEX_VERSION: u32 = 3;
+const INDEX_HEADER_SIZE: usize = 64;
+const INDEX_CHECKSUM_AT: usize = 48;
+
+const FNV1A_INIT: u64 = 0xcbf29ce484222325;
+
+fn fnv1a(mut h: u64, data: &[u8]) -> u64 {
+    for &x in data {
+        h = (h ^ (x as u64)).wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+// checksum returns the FNV-1a checksum of the data except the checksum field at the given position.
+fn checksum(data: &[u8], at: usize) -> u64 {
+    fnv1a(fnv1a(FNV1A_INIT, &data[..at]), &data[at+8..])
+}
+
+// ChecksumWriter computes the FNV-1a checksum of the bytes written through it.
+struct ChecksumWriter<W> {
+    w: W,
+    checksum: u64,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = self.w.write(data)?;
+        self.checksum = fnv1a(self.checksum, &data[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+fn write_index_sections<W: Write>(w: &mut W, index: &DatasetIndex, keys: &[u32], deleted: &[u32]) -> io::Result<()> {
+    let nentries = index.size();
+    for &nfeatures in index.nfeatures_list() {
+        w.write_all(&nfeatures.to_le_bytes())?;
+    }
+    w.write_all(&[0u8; 8][..padding(4*nentries)])?;
+    for &key in keys {
+        w.write_all(&key.to_le_bytes())?;
+    }
+    w.write_all(&[0u8; 8][..padding(4*keys.len())])?;
+    let mut offset = 0u64;
+    w.write_all(&offset.to_le_bytes())?;
+    for key in keys {
+        offset += index.indices().len(*key) as u64;
+        w.write_all(&offset.to_le_bytes())?;
+    }
+    for key in keys {
+        let mut result = Ok(());
+        index.indices().for_each(*key, |i, v| {
+            if result.is_ok() {
+                result = w.write_all(&i.to_le_bytes()).and_then(|_| { w.write_all(&v.to_bits().to_le_bytes()) });
+            }
+        });
+        result?;
+    }
+    // The label vectors are written with the updated labels.
+    let mut offset = 0u64;
+    w.write_all(&offset.to_le_bytes())?;
+    for i in 0..nentries {
+        offset += index.labels(i as u32).len() as u64;
+        w.write_all(&offset.to_le_bytes())?;
+    }
+    for i in 0..nentries {
+        for &label in index.labels(i as u32) {
+            w.write_all(&label.to_le_bytes())?;
+        }
+    }
+    w.write_all(&[0u8; 8][..padding(4*offset as usize)])?;
+    for &i in deleted {
+        w.write_all(&i.to_le_bytes())?;
+    }
+    w.write_all(&[0u8; 8][..padding(4*deleted.len())])
+}
+
+/// write_index_binary writes the index in the binary layout loadable with load_index_binary.
+/// The encoded posting lists are decoded into the plain ones.
+/// The file is replaced only after the whole index is written.
+pub fn write_index_binary<P: AsRef<Path>>(filename: P, index: &DatasetIndex) -> io::Result<()> {
+    write_file_atomically(filename.as_ref(), |f| { write_index(f, index) })
+}
+
+// write_index writes the index into the file, and fills the checksum after writing the sections.
+fn write_index(f: File, index: &DatasetIndex) -> io::Result<()> {
+    let keys = index.indices().keys();
+    let npostings = index.indices().npostings();
+    let deleted = index.deleted_ids();
+    let mut cw = ChecksumWriter{ w: BufWriter::new(f), checksum: FNV1A_INIT };
+    cw.write_all(INDEX_MAGIC)?;
+    cw.write_all(&INDEX_VERSION.to_le_bytes())?;
+    cw.write_all(&0u32.to_le_bytes())?;
+    let ynnz: usize = (0..index.size()).map(|i| { index.labels(i as u32).len() }).sum();
+    let fields = [index.size() as u64, keys.len() as u64, npostings as u64, ynnz as u64];
+    for field in &fields {
+        cw.write_all(&field.to_le_bytes())?;
+    }
+    // The checksum is filled after writing the sections.
+    cw.w.write_all(&0u64.to_le_bytes())?;
+    cw.write_all(&(deleted.len() as u64).to_le_bytes())?;
+    write_index_sections(&mut cw, index, &keys, &deleted)?;
+    let checksum = cw.checksum;
+    let mut f = cw.w.into_inner().map_err(|e| { e.into_error() })?;
+    f.seek(SeekFrom::Start(INDEX_CHECKSUM_AT as u64))?;
+    f.write_all(&checksum.to_le_bytes())?;
+    f.sync_all()
+}
+
+/// load_index_binary loads the index written by write_index_binary, verifying the checksum.
+/// The file is memory-mapped and the posting lists are used without copying, so it must not be modified while the index is alive.
+pub fn load_index_binary<P: AsRef<Path>>(filename: P) -> Result<DatasetIndex<'static>, DatasetError> {
+    if !is_mappable_platform() {
+        return Err(bad_binary("unsupported platform"));
+    }
+    let file = File::open(filename).map_err(io_error)?;
+    let mmap = Arc::new(unsafe { Mmap::map(&file) }.map_err(io_error)?);
+    if mmap.len() < INDEX_HEADER_SIZE || &mmap[0..8] != INDEX_MAGIC {
+        return Err(bad_binary("bad magic"));
+    }
+    if mmap[8..12] != INDEX_VERSION.to_le_bytes() {
+        return Err(bad_binary("unsupported version"));
+    }
+    if checksum(&mmap, INDEX_CHECKSUM_AT) != read_u64(&mmap, INDEX_CHECKSUM_AT) {
+        return Err(bad_binary("checksum mismatch"));
+    }
+    let nentries = read_u64(&mmap, 16) as usize;
+    let nkeys = read_u64(&mmap, 24) as usize;
+    let npostings = read_u64(&mmap, 32) as usize;
+    let ynnz = read_u64(&mmap, 40) as usize;
+    let ndeleted = read_u64(&mmap, 56) as usize;
+    let truncated = || { bad_binary("truncated") };
+    let mut offset = INDEX_HEADER_SIZE;
+    let nfeatures_list = unsafe { Buffer::<u32>::from_mmap(mmap.clone(), offset, nentries) }.ok_or_else(truncated)?;
+    offset += 4*nentries + padding(4*nentries);
+    let keys = unsafe { Buffer::<u32>::from_mmap(mmap.clone(), offset, nkeys) }.ok_or_else(truncated)?;
+    offset += 4*nkeys + padding(4*nkeys);
+    let key_offsets = unsafe { Buffer::<usize>::from_mmap(mmap.clone(), offset, nkeys.wrapping_add(1)) }.ok_or_else(truncated)?;
+    offset += 8*key_offsets.len();
+    let postings = unsafe { Buffer::<(u32, f32)>::from_mmap(mmap.clone(), offset, npostings) }.ok_or_else(truncated)?;
+    offset += 8*npostings;
+    // The entry ids are used for the unchecked accesses in find_nearests.
+    if postings.iter().any(|&(i, _)| { i as usize >= nentries }) {
+        return Err(bad_binary("entry id out of range"));
+    }
+    if keys.windows(2).any(|w| { w[0] >= w[1] }) {
+        return Err(bad_binary("unsorted feature keys"));
+    }
+    let postings = CsrVectors::from_parts(key_offsets, postings).ok_or_else(|| { bad_binary("inconsistent offsets of postings") })?;
+    let indices = PostingIndex::Csr(CsrPostings::from_sorted(&keys, postings));
+    let yoffsets = unsafe { Buffer::<usize>::from_mmap(mmap.clone(), offset, nentries.wrapping_add(1)) }.ok_or_else(truncated)?;
+    offset += 8*yoffsets.len();
+    let ydata = unsafe { Buffer::<u32>::from_mmap(mmap.clone(), offset, ynnz) }.ok_or_else(truncated)?;
+    offset += 4*ynnz + padding(4*ynnz);
+    let labelvecs = CsrVectors::from_parts(yoffsets, ydata).ok_or_else(|| { bad_binary("inconsistent offsets of label vectors") })?;
+    let deleted = unsafe { Buffer::<u32>::from_mmap(mmap.clone(), offset, ndeleted) }.ok_or_else(truncated)?;
+    Ok(DatasetIndex::from_parts(nfeatures_list, indices, labelvecs, &deleted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{random_dataset,temp_path};
+
+    fn is_bad_binary<T>(result: Result<T, DatasetError>) -> bool {
+        matches!(result, Err(DatasetError{ kind: DatasetErrorKind::BadBinary(_), .. }))
+    }
+
+    #[test]
+    fn load_dataset_binary_rejects_corrupted_files() {
+        let ds = random_dataset(50, 20, 5, 7);
+        let path = temp_path("corrupted-dataset.bin");
+        write_dataset_binary(&path, &ds).unwrap();
+        assert_eq!(load_dataset_binary(&path).unwrap(), ds);
+        let bytes = fs::read(&path).unwrap();
+        let with_checksum = |mut bytes: Vec<u8>| {
+            let h = checksum(&bytes, CHECKSUM_AT);
+            bytes[CHECKSUM_AT..CHECKSUM_AT+8].copy_from_slice(&h.to_le_bytes());
+            bytes
+        };
+        let mut corrupted = Vec::new();
+        // A flipped bit in the header, and in each section.
+        for &at in &[64, HEADER_SIZE + 8, HEADER_SIZE + 8*(ds.size() + 1) + 4, bytes.len() - 12] {
+            let mut bytes = bytes.clone();
+            bytes[at] ^= 1;
+            corrupted.push(bytes);
+        }
+        // The truncated file, whose checksum is valid or not.
+        corrupted.push(bytes[..bytes.len()-8].to_vec());
+        corrupted.push(with_checksum(bytes[..bytes.len()-8].to_vec()));
+        // The decreasing offsets of X with the valid checksum.
+        let mut decreasing = bytes.clone();
+        decreasing[HEADER_SIZE+8..HEADER_SIZE+16].copy_from_slice(&(ds.X.nnz() as u64 + 1).to_le_bytes());
+        corrupted.push(with_checksum(decreasing));
+        for (k, bytes) in corrupted.into_iter().enumerate() {
+            fs::write(&path, bytes).unwrap();
+            assert!(is_bad_binary(load_dataset_binary(&path)), "corruption {}", k);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_index_binary_rejects_corrupted_headers() {
+        let ds = random_dataset(50, 20, 5, 8);
+        let mut index = DatasetIndex::new(&ds);
+        index.delete(3);
+        let path = temp_path("corrupted-index.bin");
+        write_index_binary(&path, &index).unwrap();
+        let loaded = load_index_binary(&path).unwrap();
+        assert_eq!(loaded.size(), index.size());
+        assert_eq!(loaded.deleted_ids(), index.deleted_ids());
+        let bytes = fs::read(&path).unwrap();
+        for at in (0..INDEX_HEADER_SIZE).filter(|at| { !(INDEX_CHECKSUM_AT..INDEX_CHECKSUM_AT+8).contains(at) }) {
+            let mut bytes = bytes.clone();
+            bytes[at] ^= 1;
+            fs::write(&path, bytes).unwrap();
+            assert!(is_bad_binary(load_index_binary(&path)), "flipped byte {}", at);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_index_binary_writes_updated_labels() {
+        let ds = random_dataset(30, 20, 5, 10);
+        let mut index = DatasetIndex::new(&ds);
+        index.update_labels(2, &[4, 1]);
+        index.update_labels(5, &[]);
+        let path = temp_path("relabeled-index.bin");
+        write_index_binary(&path, &index).unwrap();
+        let loaded = load_index_binary(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        for i in 0..ds.size() as u32 {
+            assert_eq!(loaded.labels(i), index.labels(i));
+        }
+        assert_eq!(loaded.labels(2), [4, 1]);
+    }
+
+    #[test]
+    fn write_index_binary_keeps_old_file_on_failure() {
+        let ds = random_dataset(50, 20, 5, 9);
+        let index = DatasetIndex::new(&ds);
+        let path = temp_path("atomic-index.bin");
+        write_index_binary(&path, &index).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        // The temporary file cannot be created in place of the directory.
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::create_dir(&tmp_path).unwrap();
+        let mut other = DatasetIndex::new(&ds);
+        other.delete(0);
+        assert!(write_index_binary(&path, &other).is_err());
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+        fs::remove_dir(&tmp_path).unwrap();
+        // Otherwise, the file is replaced, and the temporary file is removed.
+        write_index_binary(&path, &other).unwrap();
+        assert_eq!(load_index_binary(&path).unwrap().deleted_ids(), [0]);
+        assert!(!Path::new(&tmp_path).exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
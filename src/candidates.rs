@@ -0,0 +1,88 @@
+#![allow(non_snake_case)]
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+use std::sync::atomic::{AtomicUsize,Ordering};
+
+use dataset::{Dataset,FeatureVectors,LabelVectors};
+use hash::BuildHasher;
+use nearest::{normalized_vectors,rescore};
+use topk::top_k;
+
+/// CandidateVectors is the training entries held by the approximate indices such as MinHashIndex, LabelNear and HnswIndex,
+/// which select some candidates for each query and rescore them with the similarity of DatasetIndex::find_nearests.
+/// This counts the candidates per query for measuring the cost of the approximation.
+pub struct CandidateVectors<'a> {
+    // vectors is the L2-normalized feature vectors sorted by the features.
+    vectors: FeatureVectors,
+    labelvecs: Cow<'a, LabelVectors>,
+    nqueries: AtomicUsize,
+    ncandidates: AtomicUsize,
+}
+
+impl<'a> CandidateVectors<'a> {
+    pub fn new(ds: &'a Dataset) -> CandidateVectors<'a> {
+        CandidateVectors{
+            vectors: normalized_vectors(ds),
+            labelvecs: Cow::Borrowed(&ds.Y),
+            nqueries: AtomicUsize::new(0),
+            ncandidates: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.vectors.len()
+    }
+
+    /// vectors returns the L2-normalized feature vectors sorted by the features.
+    pub fn vectors(&self) -> &FeatureVectors {
+        &self.vectors
+    }
+
+    /// labels returns the labels of the entry.
+    pub fn labels(&self, i: u32) -> &[u32] {
+        &self.labelvecs[i as usize]
+    }
+
+    /// count_query counts a query with its number of the candidates.
+    pub fn count_query(&self, ncandidates: usize) {
+        self.nqueries.fetch_add(1, Ordering::Relaxed);
+        self.ncandidates.fetch_add(ncandidates, Ordering::Relaxed);
+    }
+
+    /// mean_candidates returns the mean number of the candidates counted per query.
+    pub fn mean_candidates(&self) -> f32 {
+        let nqueries = self.nqueries.load(Ordering::Relaxed);
+        if nqueries == 0 {
+            return 0.0;
+        }
+        (self.ncandidates.load(Ordering::Relaxed) as f32)/(nqueries as f32)
+    }
+
+    /// rescore_top_k returns the top-S (entry, similarity) pairs of the candidates with the similarities of DatasetIndex::find_nearests.
+    /// index_sims is the buffer of the rescored candidates, which is left empty.
+    pub fn rescore_top_k<I: IntoIterator<Item=u32>>(&self, candidates: I, xi: &[(u32, f32)], S: usize, beta: f32, index_sims: &mut Vec<(u32, f32)>) -> Vec<(u32, f32)> {
+        for i in candidates {
+            if let Some(sim) = rescore(&self.vectors[i as usize], xi, beta) {
+                index_sims.push((i, sim));
+            }
+        }
+        let top = top_k(index_sims, S);
+        index_sims.clear();
+        top
+    }
+
+    /// memory_size returns the number of the bytes used by the vectors.
+    pub fn memory_size(&self) -> usize {
+        mem::size_of_val(self.vectors.offsets()) + mem::size_of_val(self.vectors.data())
+    }
+}
+
+/// tables_size returns the number of the bytes used by the hash tables of the entries.
+pub fn tables_size<K: Eq + Hash>(tables: &[HashMap<K, Vec<u32>, BuildHasher>]) -> usize {
+    tables.iter().map(|table| {
+        table.capacity()*mem::size_of::<(K, Vec<u32>)>() + table.values().map(|entries| { mem::size_of_val(&entries[..]) }).sum::<usize>()
+    }).sum()
+}
@@ -0,0 +1,264 @@
+use std::fmt;
+use std::mem;
+use std::ops::{Deref,Index};
+use std::slice;
+use std::sync::Arc;
+
+use memmap2::Mmap;
+
+/// Buffer is a slice either owned on the heap or borrowed from a memory-mapped file.
+/// The mapped buffer is copied onto the heap at the first mutation.
+pub struct Buffer<T> {
+    repr: BufferRepr<T>,
+}
+
+enum BufferRepr<T> {
+    Owned(Vec<T>),
+    Mapped{ mmap: Arc<Mmap>, offset: usize, len: usize },
+}
+
+impl<T: Copy> Buffer<T> {
+    /// from_mmap returns the buffer of the len elements at offset bytes in mmap.
+    /// This returns None if the range is out of mmap or misaligned.
+    ///
+    /// # Safety
+    /// Any bit pattern must be a valid T, and the mapped file must not be modified while the buffer is alive.
+    pub(crate) unsafe fn from_mmap(mmap: Arc<Mmap>, offset: usize, len: usize) -> Option<Buffer<T>> {
+        let end = len.checked_mul(mem::size_of::<T>()).and_then(|size| { size.checked_add(offset) });
+        match end {
+            Some(end) if end <= mmap.len() && (mmap.as_ptr() as usize + offset).is_multiple_of(mem::align_of::<T>()) => {
+                Some(Buffer{ repr: BufferRepr::Mapped{ mmap, offset, len } })
+            },
+            _ => None,
+        }
+    }
+
+    /// is_mapped returns true if the buffer is borrowed from a memory-mapped file.
+    pub fn is_mapped(&self) -> bool {
+        match self.repr {
+            BufferRepr::Owned(_) => false,
+            BufferRepr::Mapped{ .. } => true,
+        }
+    }
+
+    /// to_mut returns the mutable vector, copying the mapped buffer onto the heap if needed.
+    pub fn to_mut(&mut self) -> &mut Vec<T> {
+        if self.is_mapped() {
+            self.repr = BufferRepr::Owned(self.to_vec());
+        }
+        match self.repr {
+            BufferRepr::Owned(ref mut v) => v,
+            BufferRepr::Mapped{ .. } => unreachable!(),
+        }
+    }
+}
+
+impl<T> Deref for Buffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self.repr {
+            BufferRepr::Owned(ref v) => v,
+            BufferRepr::Mapped{ ref mmap, offset, len } => unsafe {
+                slice::from_raw_parts(mmap.as_ptr().add(offset) as *const T, len)
+            },
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for Buffer<T> {
+    fn from(v: Vec<T>) -> Buffer<T> {
+        Buffer{ repr: BufferRepr::Owned(v) }
+    }
+}
+
+impl<T: Clone> Clone for Buffer<T> {
+    fn clone(&self) -> Buffer<T> {
+        match self.repr {
+            BufferRepr::Owned(ref v) => Buffer{ repr: BufferRepr::Owned(v.clone()) },
+            BufferRepr::Mapped{ ref mmap, offset, len } => Buffer{ repr: BufferRepr::Mapped{ mmap: mmap.clone(), offset, len } },
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Buffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: PartialEq> PartialEq for Buffer<T> {
+    fn eq(&self, other: &Buffer<T>) -> bool {
+        **self == **other
+    }
+}
+
+/// CsrVectors is a list of variable-length vectors stored in the compressed sparse row (CSR) format.
+/// The i-th vector is data[offsets[i]..offsets[i+1]], so each vector costs only one offset.
+#[derive(Clone,Debug,PartialEq)]
+pub struct CsrVectors<T> {
+    offsets: Buffer<usize>,
+    data: Buffer<T>,
+}
+
+impl<T: Copy> CsrVectors<T> {
+    pub fn new() -> CsrVectors<T> {
+        CsrVectors{
+            offsets: Buffer::from(vec![0]),
+            data: Buffer::from(Vec::new()),
+        }
+    }
+
+    /// with_capacity returns the empty vectors preallocated for n vectors with nnz elements in total.
+    pub fn with_capacity(n: usize, nnz: usize) -> CsrVectors<T> {
+        let mut offsets = Vec::with_capacity(n + 1);
+        offsets.push(0);
+        CsrVectors{
+            offsets: Buffer::from(offsets),
+            data: Buffer::from(Vec::with_capacity(nnz)),
+        }
+    }
+
+    /// from_parts returns the vectors consisting of the given offsets and data.
+    /// This returns None if the offsets are inconsistent with the data, i.e., they do not increase from 0 to the length of the data.
+    pub fn from_parts(offsets: Buffer<usize>, data: Buffer<T>) -> Option<CsrVectors<T>> {
+        if offsets.first() != Some(&0) || offsets.last() != Some(&data.len()) || offsets.windows(2).any(|w| { w[0] > w[1] }) {
+            return None;
+        }
+        Some(CsrVectors{ offsets, data })
+    }
+
+    /// into_parts returns the offsets and the data of the vectors.
+    pub fn into_parts(self) -> (Buffer<usize>, Buffer<T>) {
+        (self.offsets, self.data)
+    }
+
+    /// offsets returns the offsets of the vectors in data, whose length is len()+1.
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    /// data returns all the elements of the vectors concatenated.
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+
+    /// is_mapped returns true if the vectors are borrowed from a memory-mapped file.
+    pub fn is_mapped(&self) -> bool {
+        self.offsets.is_mapped() || self.data.is_mapped()
+    }
+
+    /// try_reserve tries to reserve the capacity for additional n vectors, ignoring the allocation failures.
+    pub fn try_reserve(&mut self, n: usize) {
+        let _ = self.offsets.to_mut().try_reserve(n);
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// nnz returns the total number of the elements.
+    pub fn nnz(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn get(&self, i: usize) -> Option<&[T]> {
+        if i < self.len() {
+            Some(&self.data[self.offsets[i]..self.offsets[i+1]])
+        } else {
+            None
+        }
+    }
+
+    pub fn push(&mut self, v: &[T]) {
+        let data = self.data.to_mut();
+        data.extend_from_slice(v);
+        self.offsets.to_mut().push(data.len());
+    }
+
+    /// resize truncates the vectors, or appends the empty vectors, so that the length is n.
+    pub fn resize(&mut self, n: usize) {
+        if n < self.len() {
+            let nnz = self.offsets[n];
+            self.offsets.to_mut().truncate(n + 1);
+            self.data.to_mut().truncate(nnz);
+        } else {
+            let nnz = self.data.len();
+            self.offsets.to_mut().resize(n + 1, nnz);
+        }
+    }
+
+    /// retain keeps only the elements accepted by the predicates in place, shifting the following elements.
+    /// predicate is called with each vector, and returns the predicate of the elements of the vector.
+    pub fn retain<P: FnMut(&T) -> bool, F: FnMut(&[T]) -> P>(&mut self, mut predicate: F) {
+        let (offsets, data) = (self.offsets.to_mut(), self.data.to_mut());
+        let (mut start, mut n) = (0, 0);
+        for offset in offsets.iter_mut().skip(1) {
+            let end = *offset;
+            let mut keep = predicate(&data[start..end]);
+            for j in start..end {
+                let x = data[j];
+                if keep(&x) {
+                    data[n] = x;
+                    n += 1;
+                }
+            }
+            *offset = n;
+            start = end;
+        }
+        data.truncate(n);
+    }
+
+    pub fn iter(&self) -> CsrVectorsIterator<'_, T> {
+        CsrVectorsIterator{
+            offsets: self.offsets.windows(2),
+            data: &self.data,
+        }
+    }
+}
+
+impl<T: Copy> Default for CsrVectors<T> {
+    fn default() -> CsrVectors<T> {
+        CsrVectors::new()
+    }
+}
+
+impl<T: Copy> Index<usize> for CsrVectors<T> {
+    type Output = [T];
+
+    fn index(&self, i: usize) -> &[T] {
+        &self.data[self.offsets[i]..self.offsets[i+1]]
+    }
+}
+
+impl<'a, T: Copy> IntoIterator for &'a CsrVectors<T> {
+    type Item = &'a [T];
+    type IntoIter = CsrVectorsIterator<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+pub struct CsrVectorsIterator<'a, T: 'a> {
+    offsets: slice::Windows<'a, usize>,
+    data: &'a [T],
+}
+
+impl<'a, T> Iterator for CsrVectorsIterator<'a, T> {
+    type Item = &'a [T];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.offsets.next().map(|w| { &self.data[w[0]..w[1]] })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.offsets.size_hint()
+    }
+}
+
+impl<'a, T> ExactSizeIterator for CsrVectorsIterator<'a, T> {}
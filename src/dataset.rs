@@ -0,0 +1,839 @@
+#![allow(non_snake_case)]
+
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self,BufRead,BufReader,BufWriter,Read,Write};
+use std::path::{Path,PathBuf};
+use std::str::FromStr;
+
+use flate2::bufread::MultiGzDecoder;
+use zstd;
+
+use csr::CsrVectors;
+
+pub type FeatureVector = Vec<(u32, f32)>;
+pub type FeatureVectors = CsrVectors<(u32, f32)>;
+
+pub type LabelVector = Vec<u32>;
+pub type LabelVectors = CsrVectors<u32>;
+
+/// DatasetHeader is the header line `N D L` of the Extreme Classification Repository format.
+#[derive(Clone,Copy,Debug,Default,Eq,PartialEq)]
+pub struct DatasetHeader {
+    pub nentries: usize,
+    pub nfeatures: usize,
+    pub nlabels: usize,
+}
+
+impl FromStr for DatasetHeader {
+    type Err = DatasetErrorKind;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace().map(|word| { word.parse::<usize>() });
+        match (words.next(), words.next(), words.next(), words.next()) {
+            (Some(Ok(nentries)), Some(Ok(nfeatures)), Some(Ok(nlabels)), None) => {
+                Ok(DatasetHeader{ nentries, nfeatures, nlabels })
+            },
+            _ => Err(DatasetErrorKind::BadHeader(s.to_string())),
+        }
+    }
+}
+
+/// Dataset is a table of the feature vectors X and the label vectors Y stored in the CSR format.
+/// nfeatures (resp. nlabels) is the dimension of the feature (resp. label) space, which is larger than any feature key (resp. label).
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Dataset {
+    pub X: FeatureVectors,
+    pub Y: LabelVectors,
+    pub nfeatures: usize,
+    pub nlabels: usize,
+}
+
+impl Dataset {
+    pub fn new() -> Dataset {
+        Dataset{
+            X: FeatureVectors::new(),
+            Y: LabelVectors::new(),
+            nfeatures: 0,
+            nlabels: 0,
+        }
+    }
+    /// header returns the header describing the dataset.
+    pub fn header(&self) -> DatasetHeader {
+        DatasetHeader{
+            nentries: self.size(),
+            nfeatures: self.nfeatures,
+            nlabels: self.nlabels,
+        }
+    }
+    /// push appends the entry, extending nfeatures and nlabels if needed.
+    pub fn push(&mut self, xi: &[(u32, f32)], yi: &[u32]) {
+        for &(key, _) in xi {
+            self.nfeatures = self.nfeatures.max(key as usize + 1);
+        }
+        for &label in yi {
+            self.nlabels = self.nlabels.max(label as usize + 1);
+        }
+        self.X.push(xi);
+        self.Y.push(yi);
+    }
+    pub fn resize(&mut self, n: usize) {
+        self.X.resize(n);
+        self.Y.resize(n);
+    }
+    pub fn size(&self) -> usize {
+        self.X.len()
+    }
+}
+
+impl<'a> IntoIterator for &'a Dataset {
+    type Item = (&'a [(u32, f32)], &'a [u32]);
+    type IntoIter = DatasetIterator<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        DatasetIterator{ ds: self, index: 0 }
+    }
+}
+
+pub struct DatasetIterator<'a> {
+    ds: &'a Dataset,
+    index: usize
+}
+
+impl<'a> Iterator for DatasetIterator<'a> {
+    type Item = (&'a [(u32, f32)], &'a [u32]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.index;
+        if index >= self.ds.size() {
+            return None;
+        }
+        self.index += 1;
+        Some((&self.ds.X[index], &self.ds.Y[index]))
+    }
+}
+
+/// DatasetErrorKind is the kind of DatasetError.
+#[derive(Debug)]
+pub enum DatasetErrorKind {
+    Io(io::Error),
+    BadLabel(String),
+    BadFeatureKey(String),
+    BadValue(String),
+    MissingColon(String),
+    BadHeader(String),
+    FeatureKeyOutOfRange(u32),
+    LabelOutOfRange(u32),
+    EntryCountMismatch{ expected: usize, actual: usize },
+    BadBinary(String),
+}
+
+/// DatasetError is the error in reading a dataset, located at the 1-origin line and column.
+/// The line (resp. column) is 0 if the error is not related to any line (resp. column), e.g. failures in opening files.
+/// The path is set if the dataset consists of multiple files.
+#[derive(Debug)]
+pub struct DatasetError {
+    pub path: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+    pub kind: DatasetErrorKind,
+}
+
+impl DatasetError {
+    pub fn new(line: usize, column: usize, kind: DatasetErrorKind) -> DatasetError {
+        DatasetError{ path: None, line, column, kind }
+    }
+
+    /// with_path returns the error located in the given file.
+    pub fn with_path<P: AsRef<Path>>(self, path: P) -> DatasetError {
+        DatasetError{ path: Some(path.as_ref().to_path_buf()), ..self }
+    }
+}
+
+impl fmt::Display for DatasetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref path) = self.path {
+            write!(f, "{:?}, ", path)?;
+        }
+        write!(f, "line {}, column {}: ", self.line, self.column)?;
+        match self.kind {
+            DatasetErrorKind::Io(ref e) => write!(f, "{}", e),
+            DatasetErrorKind::BadLabel(ref s) => write!(f, "bad label {:?}", s),
+            DatasetErrorKind::BadFeatureKey(ref s) => write!(f, "bad feature key {:?}", s),
+            DatasetErrorKind::BadValue(ref s) => write!(f, "bad value {:?}", s),
+            DatasetErrorKind::MissingColon(ref s) => write!(f, "missing colon in {:?}", s),
+            DatasetErrorKind::BadHeader(ref s) => write!(f, "bad header {:?}", s),
+            DatasetErrorKind::FeatureKeyOutOfRange(key) => write!(f, "feature key {} out of range", key),
+            DatasetErrorKind::LabelOutOfRange(label) => write!(f, "label {} out of range", label),
+            DatasetErrorKind::EntryCountMismatch{ expected, actual } => write!(f, "expected {} entries, but got {}", expected, actual),
+            DatasetErrorKind::BadBinary(ref s) => write!(f, "bad binary: {}", s),
+        }
+    }
+}
+
+impl error::Error for DatasetError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self.kind {
+            DatasetErrorKind::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Dialect is the variation of the line format of the datasets.
+/// The default is the strict format of the Extreme Classification Repository.
+#[derive(Clone,Debug,Default,Eq,PartialEq)]
+pub struct Dialect {
+    /// The index base of the feature keys in the file (e.g. 1 for LIBSVM), which is subtracted from the keys.
+    pub feature_base: u32,
+    /// If true, the words are separated by any runs of whitespaces, and the leading words without colons are the labels.
+    /// Hence, the label list may contain spaces or empty labels, or may be omitted.
+    pub tolerant: bool,
+    /// If true, the texts after `#` are stripped as comments, and the blank lines are ignored.
+    pub comments: bool,
+    /// If true, the `qid:` words are ignored.
+    pub skip_qid: bool,
+}
+
+impl Dialect {
+    /// libsvm returns the dialect of LIBSVM/SVMlight files.
+    pub fn libsvm() -> Dialect {
+        Dialect{
+            feature_base: 1,
+            tolerant: true,
+            comments: true,
+            skip_qid: true,
+        }
+    }
+}
+
+/// ReadOptions is the options of read_dataset_with_options.
+#[derive(Clone,Debug,Default)]
+pub struct ReadOptions {
+    /// The line format of the entries.
+    pub dialect: Dialect,
+    /// If true, the malformed lines are skipped and counted instead of aborting.
+    pub skip_bad_lines: bool,
+    /// If true, the file has no header line, and the dimensions are inferred from the entries.
+    pub headerless: bool,
+    /// If true, the mismatch of the entry count against the header is an error instead of a warning.
+    pub strict_header: bool,
+}
+
+impl ReadOptions {
+    /// libsvm returns the options for headerless LIBSVM/SVMlight files.
+    pub fn libsvm() -> ReadOptions {
+        ReadOptions{
+            dialect: Dialect::libsvm(),
+            headerless: true,
+            ..ReadOptions::default()
+        }
+    }
+}
+
+/// ReadSummary is the summary of read_dataset_with_options.
+#[derive(Clone,Debug,Default)]
+pub struct ReadSummary {
+    /// The number of the skipped malformed lines.
+    pub nskipped: usize,
+}
+
+// column_of returns the 1-origin column of the word in the line.
+fn column_of(line: &str, word: &str) -> usize {
+    word.as_ptr() as usize - line.as_ptr() as usize + 1
+}
+
+// parse_entry parses the line into the given buffers, which are cleared at first.
+fn parse_entry(line: &str, lineno: usize, header: Option<&DatasetHeader>, dialect: &Dialect, features: &mut FeatureVector, labels: &mut LabelVector) -> Result<(), DatasetError> {
+    features.clear();
+    labels.clear();
+    let nlabels = header.map(|header| { header.nlabels });
+    let nfeatures = header.map(|header| { header.nfeatures });
+    if dialect.tolerant {
+        // The leading words without any colon are the label list.
+        let mut words = line.split_whitespace().peekable();
+        while let Some(&word) = words.peek() {
+            if word.contains(':') {
+                break;
+            }
+            parse_labels(line, word, lineno, nlabels, dialect, labels)?;
+            words.next();
+        }
+        parse_features(line, words, lineno, nfeatures, dialect, features)
+    } else {
+        let mut words = line.split(' ');
+        let labels_word = words.next().unwrap();
+        // The empty label word means that the entry has no label.
+        if !labels_word.is_empty() {
+            parse_labels(line, labels_word, lineno, nlabels, dialect, labels)?;
+        }
+        parse_features(line, words, lineno, nfeatures, dialect, features)
+    }
+}
+
+// parse_labels parses the comma-separated labels in the word into labels.
+// If nlabels is given, the labels must be smaller than it.
+fn parse_labels(line: &str, word: &str, lineno: usize, nlabels: Option<usize>, dialect: &Dialect, labels: &mut LabelVector) -> Result<(), DatasetError> {
+    for s in word.split(',') {
+        if s.is_empty() && dialect.tolerant {
+            continue;
+        }
+        let label: u32 = match s.parse() {
+            Ok(label) => label,
+            Err(_) => return Err(DatasetError::new(lineno, column_of(line, s), DatasetErrorKind::BadLabel(s.to_string()))),
+        };
+        if let Some(nlabels) = nlabels {
+            if label as usize >= nlabels {
+                return Err(DatasetError::new(lineno, column_of(line, s), DatasetErrorKind::LabelOutOfRange(label)));
+            }
+        }
+        labels.push(label);
+    }
+    Ok(())
+}
+
+// parse_features parses the key:value words in the line into features.
+// If nfeatures is given, the keys shifted by the feature base must be smaller than it.
+pub(crate) fn parse_features<'a, I: Iterator<Item = &'a str>>(line: &str, words: I, lineno: usize, nfeatures: Option<usize>, dialect: &Dialect, features: &mut FeatureVector) -> Result<(), DatasetError> {
+    for s in words {
+        if dialect.skip_qid && s.starts_with("qid:") {
+            continue;
+        }
+        let column = column_of(line, s);
+        let colon = match s.find(':') {
+            Some(colon) => colon,
+            None => return Err(DatasetError::new(lineno, column, DatasetErrorKind::MissingColon(s.to_string()))),
+        };
+        let key: u32 = match s[..colon].parse::<u32>() {
+            Ok(key) if key >= dialect.feature_base => key - dialect.feature_base,
+            _ => return Err(DatasetError::new(lineno, column, DatasetErrorKind::BadFeatureKey(s[..colon].to_string()))),
+        };
+        if let Some(nfeatures) = nfeatures {
+            if key as usize >= nfeatures {
+                return Err(DatasetError::new(lineno, column, DatasetErrorKind::FeatureKeyOutOfRange(key)));
+            }
+        }
+        let value: f32 = match s[colon+1..].parse() {
+            Ok(value) => value,
+            Err(_) => return Err(DatasetError::new(lineno, column + colon + 1, DatasetErrorKind::BadValue(s[colon+1..].to_string()))),
+        };
+        features.push((key, value));
+    }
+    Ok(())
+}
+
+/// read_dataset reads the dataset in the Extreme Classification Repository format.
+/// See open_dataset for the supported compressions and the standard input.
+pub fn read_dataset<P: AsRef<Path>>(filename: P) -> Result<Dataset, DatasetError> {
+    read_dataset_with_options(filename, &ReadOptions::default()).map(|(ds, _)| ds)
+}
+
+/// Compression is the compression format of dataset files.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// detect detects the compression from the magic bytes at the head of the file.
+    /// If the head is shorter than 4 bytes, i.e. the whole file is, this detects the compression from the file extension.
+    pub fn detect(head: &[u8], filename: &Path) -> Compression {
+        if head.len() >= 4 {
+            if head.starts_with(&[0x1f, 0x8b]) {
+                Compression::Gzip
+            } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+                Compression::Zstd
+            } else {
+                Compression::None
+            }
+        } else {
+            match filename.extension().and_then(|ext| { ext.to_str() }) {
+                Some("gz") => Compression::Gzip,
+                Some("zst") => Compression::Zstd,
+                _ => Compression::None,
+            }
+        }
+    }
+}
+
+// decompress returns the reader decompressing the given reader.
+// The head is read until 4 bytes or the end, because a read from a pipe may return fewer bytes, and chained back in front of the rest.
+fn decompress<R: BufRead + 'static>(mut reader: R, filename: &Path) -> io::Result<Box<dyn BufRead>> {
+    let mut head = [0u8; 4];
+    let mut n = 0;
+    while n < head.len() {
+        match reader.read(&mut head[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    let compression = Compression::detect(&head[..n], filename);
+    debug!("reading {:?} with compression {:?}", filename, compression);
+    let reader = io::Cursor::new(head[..n].to_vec()).chain(reader);
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(reader)?)),
+    })
+}
+
+/// open_dataset opens the file for reading, decompressing gzip or zstd transparently.
+/// The filename "-" means the standard input.
+pub fn open_dataset<P: AsRef<Path>>(filename: P) -> io::Result<Box<dyn BufRead>> {
+    let filename = filename.as_ref();
+    if filename == Path::new("-") {
+        decompress(BufReader::new(io::stdin()), filename)
+    } else {
+        decompress(BufReader::new(File::open(filename)?), filename)
+    }
+}
+
+/// read_dataset_with_options is read_dataset with the given options.
+pub fn read_dataset_with_options<P: AsRef<Path>>(filename: P, opts: &ReadOptions) -> Result<(Dataset, ReadSummary), DatasetError> {
+    let reader = open_dataset(filename).map_err(|e| DatasetError::new(0, 0, DatasetErrorKind::Io(e)))?;
+    read_dataset_from(reader, opts)
+}
+
+/// read_dataset_from is read_dataset_with_options reading from the given reader.
+pub fn read_dataset_from<R: BufRead>(reader: R, opts: &ReadOptions) -> Result<(Dataset, ReadSummary), DatasetError> {
+    let mut reader = DatasetReader::new(reader, opts)?;
+    let mut ds = Dataset::new();
+    if let Some(header) = reader.header() {
+        // Do not trust the header too much for preallocating.
+        ds.X.try_reserve(header.nentries);
+        ds.Y.try_reserve(header.nentries);
+        ds.nfeatures = header.nfeatures;
+        ds.nlabels = header.nlabels;
+    }
+    let (mut xi, mut yi) = (FeatureVector::new(), LabelVector::new());
+    while reader.read_entry(&mut xi, &mut yi)? {
+        ds.push(&xi, &yi);
+    }
+    Ok((ds, reader.summary()))
+}
+
+/// DatasetReader reads the entries of a dataset one by one, without loading the whole dataset.
+/// As an iterator, this yields the owned entries, and stops after the first error.
+pub struct DatasetReader<R> {
+    lines: io::Lines<R>,
+    lineno: usize,
+    header: Option<DatasetHeader>,
+    opts: ReadOptions,
+    nentries: usize,
+    nskipped: usize,
+    done: bool,
+}
+
+impl DatasetReader<Box<dyn BufRead>> {
+    /// open returns the reader of the file opened with open_dataset.
+    pub fn open<P: AsRef<Path>>(filename: P, opts: &ReadOptions) -> Result<DatasetReader<Box<dyn BufRead>>, DatasetError> {
+        let reader = open_dataset(filename).map_err(|e| DatasetError::new(0, 0, DatasetErrorKind::Io(e)))?;
+        DatasetReader::new(reader, opts)
+    }
+}
+
+impl<R: BufRead> DatasetReader<R> {
+    /// new returns the reader from the given reader, reading the header line unless opts.headerless.
+    pub fn new(reader: R, opts: &ReadOptions) -> Result<DatasetReader<R>, DatasetError> {
+        let mut lines = reader.lines();
+        let mut lineno = 0;
+        let header = if opts.headerless {
+            None
+        } else {
+            lineno += 1;
+            let line = match lines.next() {
+                Some(line) => line.map_err(|e| DatasetError::new(lineno, 0, DatasetErrorKind::Io(e)))?,
+                None => String::new(),
+            };
+            Some(line.parse::<DatasetHeader>().map_err(|kind| DatasetError::new(lineno, 1, kind))?)
+        };
+        Ok(DatasetReader{
+            lines,
+            lineno,
+            header,
+            opts: opts.clone(),
+            nentries: 0,
+            nskipped: 0,
+            done: false,
+        })
+    }
+
+    /// header returns the header, or None if the reader is headerless.
+    pub fn header(&self) -> Option<&DatasetHeader> {
+        self.header.as_ref()
+    }
+
+    /// summary returns the summary of the entries read so far.
+    pub fn summary(&self) -> ReadSummary {
+        ReadSummary{ nskipped: self.nskipped }
+    }
+
+    /// read_entry reads the next entry into the given buffers, and returns false at the end.
+    /// At the end, the entry count is checked against the header.
+    pub fn read_entry(&mut self, xi: &mut FeatureVector, yi: &mut LabelVector) -> Result<bool, DatasetError> {
+        if self.done {
+            return Ok(false);
+        }
+        for line in &mut self.lines {
+            self.lineno += 1;
+            let lineno = self.lineno;
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    self.done = true;
+                    return Err(DatasetError::new(lineno, 0, DatasetErrorKind::Io(e)));
+                },
+            };
+            let line = if self.opts.dialect.comments {
+                let line = match line.find('#') {
+                    Some(sharp) => &line[..sharp],
+                    None => &line[..],
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                line
+            } else {
+                &line[..]
+            };
+            match parse_entry(line, lineno, self.header.as_ref(), &self.opts.dialect, xi, yi) {
+                Ok(()) => {
+                    self.nentries += 1;
+                    return Ok(true);
+                },
+                Err(e) => {
+                    if !self.opts.skip_bad_lines {
+                        self.done = true;
+                        return Err(e);
+                    }
+                    debug!("skipped the malformed line: {}", e);
+                    self.nskipped += 1;
+                },
+            }
+        }
+        self.done = true;
+        if let Some(header) = self.header {
+            let nentries = self.nentries + self.nskipped;
+            if nentries != header.nentries {
+                // The mismatch is located at the last line read.
+                let e = DatasetError::new(self.lineno, 0, DatasetErrorKind::EntryCountMismatch{ expected: header.nentries, actual: nentries });
+                if self.opts.strict_header {
+                    return Err(e);
+                }
+                warn!("{}", e);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl<R: BufRead> Iterator for DatasetReader<R> {
+    type Item = Result<(FeatureVector, LabelVector), DatasetError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (mut xi, mut yi) = (FeatureVector::new(), LabelVector::new());
+        match self.read_entry(&mut xi, &mut yi) {
+            Ok(true) => Some(Ok((xi, yi))),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// WriteOptions is the options of write_dataset_with_options.
+#[derive(Clone,Debug,Default)]
+pub struct WriteOptions {
+    /// The number of the digits after the decimal point of the feature values.
+    /// If None, the shortest representation read back as the same value is used.
+    /// Otherwise, the values are rounded to the digits, so the dataset read back is lossy, e.g., the values smaller than the last digit become zero.
+    pub precision: Option<usize>,
+}
+
+/// write_dataset writes the dataset in the Extreme Classification Repository format.
+/// read_dataset reads back the same dataset written by this, unlike write_dataset_with_options with WriteOptions::precision.
+pub fn write_dataset<P: AsRef<Path>>(filename: P, ds: &Dataset) -> io::Result<()> {
+    write_dataset_with_options(filename, ds, &WriteOptions::default())
+}
+
+/// write_dataset_with_options is write_dataset with the given options.
+pub fn write_dataset_with_options<P: AsRef<Path>>(filename: P, ds: &Dataset, opts: &WriteOptions) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(filename)?);
+    write_dataset_to(&mut w, ds, opts)?;
+    w.flush()
+}
+
+/// write_dataset_to writes the dataset into the given writer.
+pub fn write_dataset_to<W: Write>(w: &mut W, ds: &Dataset, opts: &WriteOptions) -> io::Result<()> {
+    let header = ds.header();
+    writeln!(w, "{} {} {}", header.nentries, header.nfeatures, header.nlabels)?;
+    for (xi, yi) in ds {
+        for (k, label) in yi.iter().enumerate() {
+            if k > 0 {
+                write!(w, ",")?;
+            }
+            write!(w, "{}", label)?;
+        }
+        for &(key, value) in xi {
+            match opts.precision {
+                Some(precision) => write!(w, " {}:{:.*}", key, precision, value)?,
+                None => write!(w, " {}:{}", key, value)?,
+            }
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use flate2;
+    use flate2::write::GzEncoder;
+    use testutil::{random_dataset,temp_path};
+
+    // edge_dataset returns random_dataset with the entries of no labels, no features, and the values needing all the digits.
+    fn edge_dataset() -> Dataset {
+        let mut ds = random_dataset(30, 20, 5, 12);
+        ds.push(&[], &[]);
+        ds.push(&[(0, 0.1), (3, 1.0/3.0), (7, -2.5e-7), (19, 123456.79)], &[]);
+        ds.push(&[], &[0, 4]);
+        ds.push(&[(5, f32::MIN_POSITIVE), (6, -0.0)], &[1]);
+        ds
+    }
+
+    #[test]
+    fn write_dataset_round_trips() {
+        let ds = edge_dataset();
+        let path = temp_path("round-trip.txt");
+        write_dataset(&path, &ds).unwrap();
+        assert_eq!(read_dataset(&path).unwrap(), ds);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_dataset_with_precision_rounds_values() {
+        let ds = edge_dataset();
+        let mut buf = Vec::new();
+        write_dataset_to(&mut buf, &ds, &WriteOptions{ precision: Some(3) }).unwrap();
+        let (actual, _) = read_dataset_from(&buf[..], &ReadOptions::default()).unwrap();
+        let mut expected = Dataset::new();
+        for (xi, yi) in &ds {
+            let xi: Vec<_> = xi.iter().map(|&(key, value)| { (key, format!("{:.3}", value).parse::<f32>().unwrap()) }).collect();
+            expected.push(&xi, yi);
+        }
+        assert_eq!(actual, expected);
+        assert_ne!(actual, ds);
+        // The tiny values are rounded to zero, but the features are kept.
+        assert_eq!(actual.X[ds.size()-1], [(5, 0.0), (6, 0.0)]);
+    }
+
+    fn read_text(text: &str, opts: &ReadOptions) -> Result<(Dataset, ReadSummary), DatasetError> {
+        read_dataset_from(text.as_bytes(), opts)
+    }
+
+    fn entries_of(ds: &Dataset) -> Vec<(FeatureVector, LabelVector)> {
+        ds.into_iter().map(|(xi, yi)| { (xi.to_vec(), yi.to_vec()) }).collect()
+    }
+
+    #[test]
+    fn libsvm_dialect_reads_1_origin_keys() {
+        let text = "1 1:0.5 3:1.5\n2 qid:7 2:1 4:-2\n3:0.25\n";
+        let (ds, _) = read_text(text, &ReadOptions::libsvm()).unwrap();
+        assert_eq!(entries_of(&ds), vec![
+            (vec![(0, 0.5), (2, 1.5)], vec![1]),
+            (vec![(1, 1.0), (3, -2.0)], vec![2]),
+            (vec![(2, 0.25)], vec![]),
+        ]);
+        assert_eq!((ds.nfeatures, ds.nlabels), (4, 3));
+    }
+
+    #[test]
+    fn libsvm_dialect_rejects_key_0() {
+        let e = read_text("1 2:1\n1 0:0.5\n", &ReadOptions::libsvm()).unwrap_err();
+        assert!(matches!(e.kind, DatasetErrorKind::BadFeatureKey(ref s) if s == "0"));
+        assert_eq!((e.line, e.column), (2, 3));
+        // The base 0 accepts the key 0.
+        let opts = ReadOptions{ dialect: Dialect{ feature_base: 0, ..Dialect::libsvm() }, ..ReadOptions::libsvm() };
+        let (ds, _) = read_text("1 0:0.5\n", &opts).unwrap();
+        assert_eq!(&ds.X[0], &[(0, 0.5)]);
+    }
+
+    #[test]
+    fn libsvm_dialect_skips_comments_and_blank_lines() {
+        let text = "# comment only\n\n   \n1 2:1 # trailing comment 3:4\n\t\n#\n2 1:2\n";
+        let (ds, summary) = read_text(text, &ReadOptions::libsvm()).unwrap();
+        assert_eq!(entries_of(&ds), vec![
+            (vec![(1, 1.0)], vec![1]),
+            (vec![(0, 2.0)], vec![2]),
+        ]);
+        assert_eq!(summary.nskipped, 0);
+    }
+
+    #[test]
+    fn libsvm_dialect_reads_labels_with_spaces() {
+        let text = "1, 2 ,3 4:1\n  5 ,6   1:1   2:2\n7,\t8 3:1\n";
+        let (ds, _) = read_text(text, &ReadOptions::libsvm()).unwrap();
+        assert_eq!(entries_of(&ds), vec![
+            (vec![(3, 1.0)], vec![1, 2, 3]),
+            (vec![(0, 1.0), (1, 2.0)], vec![5, 6]),
+            (vec![(2, 1.0)], vec![7, 8]),
+        ]);
+        // The strict dialect rejects the spaces in the label list.
+        let e = read_text("1 1 3\n1, 2 0:1\n", &ReadOptions::default()).unwrap_err();
+        assert!(matches!(e.kind, DatasetErrorKind::BadLabel(ref s) if s.is_empty()));
+        assert_eq!((e.line, e.column), (2, 3));
+    }
+
+    #[test]
+    fn parse_errors_are_located() {
+        let cases = [
+            ("3 4 5\n1 0:1\nx,2 0:1\n", 3, 1, "bad label \"x\""),
+            ("3 4 5\n1 0:1\n1,y 0:1\n", 3, 3, "bad label \"y\""),
+            ("3 4 5\n1 0:1\n1 0:1 k:2\n", 3, 7, "bad feature key \"k\""),
+            ("3 4 5\n1 0:1 2:v\n", 2, 9, "bad value \"v\""),
+            ("3 4 5\n1 0:1 3:\n", 2, 9, "bad value \"\""),            ("3 4 5\n1 0:1 2\n", 2, 7, "missing colon in \"2\""),
+        ];
+        for &(text, line, column, message) in &cases {
+            let e = read_text(text, &ReadOptions::default()).unwrap_err();
+            assert_eq!((e.line, e.column), (line, column), "{:?}", text);
+            assert_eq!(e.to_string(), format!("line {}, column {}: {}", line, column, message));
+        }
+    }
+
+    #[test]
+    fn skip_bad_lines_counts_skipped_lines() {
+        let text = "5 4 5\n1 0:1\nx 0:1\n2 1:v\n3 2:1\n1 3\n";
+        let opts = ReadOptions{ skip_bad_lines: true, ..ReadOptions::default() };
+        let (ds, summary) = read_text(text, &opts).unwrap();
+        assert_eq!(entries_of(&ds), vec![
+            (vec![(0, 1.0)], vec![1]),
+            (vec![(2, 1.0)], vec![3]),
+        ]);
+        assert_eq!(summary.nskipped, 3);
+        // Without the option, the first bad line aborts.
+        let e = read_text(text, &ReadOptions::default()).unwrap_err();
+        assert_eq!((e.line, e.column), (3, 1));
+    }
+
+    #[test]
+    fn entry_count_mismatch_is_located_at_last_line() {
+        let opts = ReadOptions{ strict_header: true, ..ReadOptions::default() };
+        let e = read_text("3 4 5\n1 0:1\n2 1:1\n", &opts).unwrap_err();
+        assert!(matches!(e.kind, DatasetErrorKind::EntryCountMismatch{ expected: 3, actual: 2 }));
+        assert_eq!((e.line, e.column), (3, 0));
+    }
+
+    // compressed returns the text compressed in the format.
+    fn compressed(text: &str, compression: Compression) -> Vec<u8> {
+        match compression {
+            Compression::None => text.as_bytes().to_vec(),
+            Compression::Gzip => {
+                let mut w = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                w.write_all(text.as_bytes()).unwrap();
+                w.finish().unwrap()
+            },
+            Compression::Zstd => zstd::encode_all(text.as_bytes(), 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn open_dataset_decompresses_by_magic_bytes() {
+        let text = "2 4 3\n1 0:1 3:0.5\n0,2 1:2\n";
+        let expected = read_text(text, &ReadOptions::default()).unwrap().0;
+        for &compression in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+            // The extension is ignored if the magic bytes are read.
+            let path = temp_path(&format!("open-{:?}.txt", compression));
+            fs::write(&path, compressed(text, compression)).unwrap();
+            let (ds, _) = read_dataset_from(open_dataset(&path).unwrap(), &ReadOptions::default()).unwrap();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(ds, expected, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn decompress_reads_head_over_short_reads() {
+        let text = "2 4 3\n1 0:1 3:0.5\n0,2 1:2\n";
+        let expected = read_text(text, &ReadOptions::default()).unwrap().0;
+        for &compression in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+            // Each read returns only one byte as a pipe may do, and the standard input has no extension.
+            let reader = BufReader::with_capacity(1, io::Cursor::new(compressed(text, compression)));
+            let (ds, _) = read_dataset_from(decompress(reader, Path::new("-")).unwrap(), &ReadOptions::default()).unwrap();
+            assert_eq!(ds, expected, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn short_files_are_detected_by_extension() {
+        assert_eq!(Compression::detect(b"\x1f\x8b", Path::new("a.txt")), Compression::None);
+        assert_eq!(Compression::detect(b"", Path::new("a.txt.gz")), Compression::Gzip);
+        assert_eq!(Compression::detect(b"", Path::new("a.txt.zst")), Compression::Zstd);
+        assert_eq!(Compression::detect(b"1 2", Path::new("-")), Compression::None);
+        // The short head is still read back.
+        let opts = ReadOptions{ headerless: true, ..ReadOptions::default() };
+        let (ds, _) = read_dataset_from(decompress(io::Cursor::new(b"0\n".to_vec()), Path::new("-")).unwrap(), &opts).unwrap();
+        assert_eq!(entries_of(&ds), vec![(vec![], vec![0])]);
+    }
+
+    #[test]
+    fn bad_header_is_rejected() {
+        for &text in &["", "3 4\n", "3 4 5 6\n", "3 x 5\n", "-1 4 5\n"] {
+            let e = read_text(text, &ReadOptions::default()).unwrap_err();
+            assert!(matches!(e.kind, DatasetErrorKind::BadHeader(_)), "{:?}", text);
+            assert_eq!((e.line, e.column), (1, 1));
+        }
+        // The headerless reader reads the line as an entry.
+        let opts = ReadOptions{ headerless: true, ..ReadOptions::default() };
+        let (ds, _) = read_text("3 4:1\n", &opts).unwrap();
+        assert_eq!(entries_of(&ds), vec![(vec![(4, 1.0)], vec![3])]);
+    }
+
+    #[test]
+    fn entry_count_mismatch_is_strict_only_if_requested() {
+        let strict = ReadOptions{ strict_header: true, ..ReadOptions::default() };
+        for &(text, expected, actual) in &[("1 4 5\n1 0:1\n2 1:1\n", 1, 2), ("3 4 5\n1 0:1\n", 3, 1)] {
+            let e = read_text(text, &strict).unwrap_err();
+            match e.kind {
+                DatasetErrorKind::EntryCountMismatch{ expected: e_expected, actual: e_actual } => assert_eq!((e_expected, e_actual), (expected, actual)),
+                ref kind => panic!("unexpected error: {:?}", kind),
+            }
+            // Otherwise, the mismatch is only warned.
+            let (ds, _) = read_text(text, &ReadOptions::default()).unwrap();
+            assert_eq!(ds.size(), actual);
+            assert_eq!((ds.nfeatures, ds.nlabels), (4, 5));
+        }
+        // The skipped lines are counted as the entries.
+        let opts = ReadOptions{ skip_bad_lines: true, ..strict };
+        let (ds, summary) = read_text("2 4 5\n1 0:1\nx 1:1\n", &opts).unwrap();
+        assert_eq!((ds.size(), summary.nskipped), (1, 1));
+    }
+
+    #[test]
+    fn feature_key_out_of_range_is_rejected() {
+        let e = read_text("2 4 5\n1 0:1 3:1\n1 1:1 4:1\n", &ReadOptions::default()).unwrap_err();
+        assert!(matches!(e.kind, DatasetErrorKind::FeatureKeyOutOfRange(4)));
+        assert_eq!((e.line, e.column), (3, 7));
+        // The key is checked after subtracting the index base.
+        let opts = ReadOptions{ dialect: Dialect::libsvm(), ..ReadOptions::default() };
+        let (ds, _) = read_text("1 4 5\n1 4:1\n", &opts).unwrap();
+        assert_eq!(&ds.X[0], &[(3, 1.0)]);
+        let e = read_text("1 4 5\n1 5:1\n", &opts).unwrap_err();
+        assert!(matches!(e.kind, DatasetErrorKind::FeatureKeyOutOfRange(4)));
+    }
+
+    #[test]
+    fn label_out_of_range_is_rejected() {
+        let e = read_text("2 4 5\n4 0:1\n1,5 1:1\n", &ReadOptions::default()).unwrap_err();
+        assert!(matches!(e.kind, DatasetErrorKind::LabelOutOfRange(5)));
+        assert_eq!((e.line, e.column), (3, 3));
+        // The headerless reader extends the dimensions instead.
+        let opts = ReadOptions{ headerless: true, ..ReadOptions::default() };
+        let (ds, _) = read_text("4 0:1\n1,5 1:1\n", &opts).unwrap();
+        assert_eq!((ds.nfeatures, ds.nlabels), (2, 6));
+    }
+}
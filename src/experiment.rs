@@ -0,0 +1,617 @@
+#![allow(non_snake_case)]
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path,PathBuf};
+use std::thread;
+use std::time::Instant;
+
+use cache::{load_index_binary,read_dataset_cached,write_index_binary};
+use dataset::{Dataset,DatasetError,DatasetReader,ReadOptions,ReadSummary,read_dataset_with_options};
+use hnsw::{HnswIndex,HnswOptions};
+use labelnear::{LabelNear,LabelNearOptions};
+use minhash::{MinHashIndex,MinHashOptions};
+use nearest::{IndexOptions,NeighborIndex,PrecisionAccumulator,PruneMethod,PruneSummary,PruningStats,RecallMeter,ScanStrategy,run_test_stream_parallel};
+use postings::WeightEncoding;
+use shard::{ShardedIndex,load_shards,write_shards};
+use split::{SplitDatasetReader,read_split_dataset};
+
+/// ExperimentOptions is the options of run_experiment, which are given by the command-line options of rusty-sticker-nearest.
+/// The errors of ExperimentOptions::validate name the conflicting command-line options.
+#[derive(Clone,Debug)]
+pub struct ExperimentOptions {
+    pub Ks: Vec<usize>,
+    pub S: usize,
+    pub alpha: f32,
+    pub beta: f32,
+    /// N is the maximum number of the tested entries.
+    pub N: usize,
+    /// per is the interval of the inspected predictions, which are not inspected if per is 0.
+    pub per: usize,
+    pub nthreads: usize,
+    pub scan_strategy: ScanStrategy,
+    pub read_opts: ReadOptions,
+    /// use_cache uses the binary caches of the datasets, which is ignored in the split-matrix format.
+    pub use_cache: bool,
+    /// split reads the datasets in the split-matrix format from the dataset root.
+    pub split: bool,
+    /// dataset_root is the directory of the tables whose paths are not given.
+    pub dataset_root: Option<PathBuf>,
+    pub train_path: Option<PathBuf>,
+    pub test_path: Option<PathBuf>,
+    /// validation_path is the table on which the pruned or quantized index is compared with the original index.
+    pub validation_path: Option<PathBuf>,
+    /// load_index is the prebuilt index read instead of the training table, which is the directory of the shards if it is a directory.
+    pub load_index: Option<PathBuf>,
+    pub save_index: Option<PathBuf>,
+    pub compress: bool,
+    /// shard_size is the number of the entries of each shard of the training set index, which is not sharded if shard_size is 0.
+    pub shard_size: usize,
+    /// prune is the method pruning the postings at prune_level, or at the minimum level fitting them into memory_budget.
+    pub prune: Option<PruneMethod>,
+    pub prune_level: Option<f32>,
+    pub memory_budget: Option<usize>,
+    pub weights: WeightEncoding,
+    /// minhash, labelnear and hnsw are the approximate indices used instead of the training set index, which are exclusive.
+    pub minhash: Option<MinHashOptions>,
+    pub labelnear: Option<LabelNearOptions>,
+    pub hnsw: Option<HnswOptions>,
+}
+
+impl Default for ExperimentOptions {
+    fn default() -> ExperimentOptions {
+        ExperimentOptions{
+            Ks: vec![1, 3, 5],
+            S: 5,
+            alpha: 1.0,
+            beta: 0.0,
+            N: usize::MAX,
+            per: 0,
+            nthreads: thread::available_parallelism().map(|n| { n.get() }).unwrap_or(1),
+            scan_strategy: ScanStrategy::default(),
+            read_opts: ReadOptions::default(),
+            use_cache: false,
+            split: false,
+            dataset_root: None,
+            train_path: None,
+            test_path: None,
+            validation_path: None,
+            load_index: None,
+            save_index: None,
+            compress: false,
+            shard_size: 0,
+            prune: None,
+            prune_level: None,
+            memory_budget: None,
+            weights: WeightEncoding::F32,
+            minhash: None,
+            labelnear: None,
+            hnsw: None,
+        }
+    }
+}
+
+impl ExperimentOptions {
+    /// validate returns the error of the conflicting options.
+    pub fn validate(&self) -> Result<(), ExperimentError> {
+        let conflict = |msg: &str| { Err(ExperimentError::Options(msg.to_string())) };
+        if self.split && (self.train_path.is_some() || self.test_path.is_some()) {
+            return conflict("--train and --test cannot be used with --split, which reads the matrices in the dataset root");
+        }
+        if let Some(ref path) = self.validation_path {
+            if self.split {
+                return conflict("--validation cannot be used with --split");
+            }
+            if path == Path::new("-") {
+                return conflict("--validation cannot be the standard input, because it is read twice");
+            }
+        }
+        if self.prune.is_some() && self.prune_level.is_none() && self.memory_budget.is_none() {
+            return conflict("specify --prune-level or --memory-budget");
+        }
+        let napproxes = [self.minhash.is_some(), self.labelnear.is_some(), self.hnsw.is_some()].iter().filter(|&&approx| { approx }).count();
+        if napproxes > 1 {
+            return conflict("--minhash-bands, --simhash-tables and --hnsw cannot be used together");
+        }
+        if napproxes > 0 && (self.load_index.is_some() || self.prune_method().is_some() || self.weights != WeightEncoding::F32) {
+            return conflict("--minhash-bands, --simhash-tables and --hnsw require the training table, and cannot be used with --load-index, --prune, --memory-budget or --weights");
+        }
+        Ok(())
+    }
+
+    // prune_method returns the pruning method, which is the default one if only the memory budget is given.
+    fn prune_method(&self) -> Option<PruneMethod> {
+        match self.prune {
+            Some(method) => Some(method),
+            None if self.memory_budget.is_some() => Some(PruneMethod::default()),
+            None => None,
+        }
+    }
+
+    // table_path returns the path of the table in the dataset root, which may be compressed.
+    fn table_path(&self, name: &str) -> Result<PathBuf, ExperimentError> {
+        let dsroot = match self.dataset_root {
+            Some(ref dsroot) => dsroot,
+            None => return Err(ExperimentError::Options(String::from("specify dataset root path"))),
+        };
+        for ext in &["", ".gz", ".zst"] {
+            let path = dsroot.join(format!("{}{}", name, ext));
+            if path.exists() {
+                return Ok(path);
+            }
+        }
+        Ok(dsroot.join(name))
+    }
+}
+
+/// ExperimentError is the error of run_experiment.
+#[derive(Debug)]
+pub enum ExperimentError {
+    /// Options is the conflict of the options.
+    Options(String),
+    Read(PathBuf, DatasetError),
+    Load(PathBuf, DatasetError),
+    Save(PathBuf, io::Error),
+    /// Budget is the memory budget which no pruning level satisfies.
+    Budget(usize),
+    /// NoTestEntries is the test table of no entries, whose precisions are undefined.
+    NoTestEntries(PathBuf),
+}
+
+impl fmt::Display for ExperimentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExperimentError::Options(ref msg) => write!(f, "{}", msg),
+            ExperimentError::Read(ref path, ref e) => write!(f, "failed to read {:?}: {}", path, e),
+            ExperimentError::Load(ref path, ref e) => write!(f, "failed to load {:?}: {}", path, e),
+            ExperimentError::Save(ref path, ref e) => write!(f, "failed to save {:?}: {}", path, e),
+            ExperimentError::Budget(budget) => write!(f, "failed to prune the posting lists into {} bytes", budget),
+            ExperimentError::NoTestEntries(ref path) => write!(f, "no entries to test in {:?}", path),
+        }
+    }
+}
+
+impl error::Error for ExperimentError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ExperimentError::Read(_, ref e) | ExperimentError::Load(_, ref e) => Some(e),
+            ExperimentError::Save(_, ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Recall is the recall of the approximate index against the training set index.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Recall {
+    pub name: &'static str,
+    pub S: usize,
+    pub recall: f32,
+    /// mean_candidates is the mean number of the candidates per query, which are the nodes whose similarities are computed for HNSW.
+    pub mean_candidates: f32,
+}
+
+/// Comparison is the comparison of the pruned or quantized index with the original index.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Comparison {
+    /// baseline is the name of the original index.
+    pub baseline: &'static str,
+    pub baseline_size: usize,
+    pub size: usize,
+    /// validation is the (K, Precision@K, the original Precision@K) on the validation table, which is empty without it.
+    pub validation: Vec<(usize, f32, f32)>,
+}
+
+/// ExperimentReport is the result of run_experiment, whose Display is the output of rusty-sticker-nearest.
+#[derive(Clone,Debug,PartialEq)]
+pub struct ExperimentReport {
+    pub ntested: usize,
+    /// precisions is the (K, Precision@K, the maximum achievable Precision@K) on the test table.
+    pub precisions: Vec<(usize, f32, f32)>,
+    pub recall: Option<Recall>,
+    /// maxscore is the statistics of MaxScore, which is set only if MaxScore is requested and used.
+    pub maxscore: Option<PruningStats>,
+    pub prune_summary: Option<PruneSummary>,
+    pub comparison: Option<Comparison>,
+}
+
+impl fmt::Display for ExperimentReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(K, avgPK, avgMaxPK) in &self.precisions {
+            writeln!(f, "Precision@{}={:5.2}/{:5.2}%", K, avgPK*100.0, avgMaxPK*100.0)?;
+        }
+        if let Some(ref recall) = self.recall {
+            writeln!(f, "Recall@{}={:5.2}% (candidates: {:.1} per query)", recall.S, recall.recall*100.0, recall.mean_candidates)?;
+        }
+        if let Some(stats) = self.maxscore {
+            writeln!(f, "Skipped postings={}/{} ({:5.2}%)", stats.nskipped, stats.npostings, (stats.nskipped as f64)/(stats.npostings.max(1) as f64)*100.0)?;
+        }
+        if let Some(summary) = self.prune_summary {
+            let (before, after) = (summary.npostings_before, summary.npostings_after);
+            writeln!(f, "Postings={} (unpruned: {}, dropped {:5.2}%)", after, before, (1.0 - (after as f64)/(before.max(1) as f64))*100.0)?;
+        }
+        if let Some(ref comparison) = self.comparison {
+            let (size, baseline_size) = (comparison.size, comparison.baseline_size);
+            writeln!(f, "Posting lists={} bytes ({}: {} bytes, saved {:5.2}%)", size, comparison.baseline, baseline_size, (1.0 - (size as f64)/(baseline_size.max(1) as f64))*100.0)?;
+            for &(K, avgPK, baselinePK) in &comparison.validation {
+                writeln!(f, "Validation Precision@{} difference={:+5.2}% ({}: {:5.2}%)", K, (avgPK - baselinePK)*100.0, comparison.baseline, baselinePK*100.0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_dataset(path: &Path, opts: &ReadOptions, use_cache: bool) -> Result<Dataset, ExperimentError> {
+    let result = if use_cache {
+        read_dataset_cached(path, opts)
+    } else {
+        read_dataset_with_options(path, opts)
+    };
+    dataset_or_error(result, path)
+}
+
+fn dataset_or_error(result: Result<(Dataset, ReadSummary), DatasetError>, path: &Path) -> Result<Dataset, ExperimentError> {
+    let (ds, summary) = result.map_err(|e| { ExperimentError::Read(path.to_path_buf(), e) })?;
+    if summary.nskipped > 0 {
+        warn!("skipped {} malformed lines in {:?}", summary.nskipped, path);
+    }
+    Ok(ds)
+}
+
+// TestOptions is the options of test.
+struct TestOptions<'a> {
+    Ks: &'a [usize],
+    maxK: usize,
+    S: usize,
+    alpha: f32,
+    beta: f32,
+    N: usize,
+    nthreads: usize,
+    use_cache: bool,
+    read_opts: &'a ReadOptions,
+    test_ds_path: &'a Path,
+    // labels_path is the path of the label matrix in the split-matrix format.
+    labels_path: Option<PathBuf>,
+}
+
+// test returns the accumulated precisions of the predictions with the index, and the number of the tested entries.
+fn test<I: NeighborIndex>(index: &I, opts: &TestOptions, per: usize) -> Result<(PrecisionAccumulator, usize), ExperimentError> {
+    let (maxK, S, alpha, beta, N, nthreads) = (opts.maxK, opts.S, opts.alpha, opts.beta, opts.N, opts.nthreads);
+    let mut acc = PrecisionAccumulator::new(opts.Ks);
+    let result = if opts.use_cache {
+        let test_ds = read_dataset(opts.test_ds_path, opts.read_opts, opts.use_cache)?;
+        let entries = test_ds.into_iter().take(N).map(Ok::<_, DatasetError>);
+        run_test_stream_parallel(index, entries, maxK, S, alpha, beta, per, &mut acc, nthreads)
+    } else if let Some(ref labels_path) = opts.labels_path {
+        SplitDatasetReader::open(opts.test_ds_path, labels_path, opts.read_opts).and_then(|mut reader| {
+            let result = run_test_stream_parallel(index, reader.by_ref().take(N), maxK, S, alpha, beta, per, &mut acc, nthreads);
+            if reader.summary().nskipped > 0 {
+                warn!("skipped {} malformed rows in {:?}", reader.summary().nskipped, opts.test_ds_path);
+            }
+            result
+        })
+    } else {
+        // The test table is streamed, so only the first N entries are read.
+        DatasetReader::open(opts.test_ds_path, opts.read_opts).and_then(|mut reader| {
+            let result = run_test_stream_parallel(index, reader.by_ref().take(N), maxK, S, alpha, beta, per, &mut acc, nthreads);
+            if reader.summary().nskipped > 0 {
+                warn!("skipped {} malformed lines in {:?}", reader.summary().nskipped, opts.test_ds_path);
+            }
+            result
+        })
+    };
+    let ntested = result.map_err(|e| { ExperimentError::Read(opts.test_ds_path.to_path_buf(), e) })?;
+    Ok((acc, ntested))
+}
+
+// measure_recall measures the recall of the approximate index against the exact index, which is None for the standard input.
+fn measure_recall<A: NeighborIndex, E: NeighborIndex>(name: &'static str, approx: &A, exact: &E, mean_candidates: f32, opts: &TestOptions) -> Result<Option<Recall>, ExperimentError> {
+    if opts.test_ds_path == Path::new("-") {
+        warn!("skipped the recall measurement, because the standard input cannot be read twice");
+        return Ok(None);
+    }
+    info!("measuring the recall of the {} index against the training set index ...", name);
+    let meter = RecallMeter::new(approx, exact);
+    test(&meter, opts, 0)?;
+    Ok(Some(Recall{ name, S: opts.S, recall: meter.recall(), mean_candidates }))
+}
+
+// load_index loads the prebuilt training set index, which is sharded if the path is a directory.
+fn load_index(index_path: &Path, shard_size: usize) -> Result<ShardedIndex<'static>, ExperimentError> {
+    info!("loading training set index from {:?}", index_path);
+    if shard_size > 0 {
+        warn!("ignored --shard-size, because the loaded index is already sharded or not");
+    }
+    let start_time = Instant::now();
+    let result = if index_path.is_dir() {
+        load_shards(index_path)
+    } else {
+        load_index_binary(index_path).map(|index| { ShardedIndex::from_shards(vec![index]) })
+    };
+    let index = result.map_err(|e| { ExperimentError::Load(index_path.to_path_buf(), e) })?;
+    let t = start_time.elapsed();
+    info!("loaded training set index with {} entries in {} shards in {}.{:03}s", index.size(), index.nshards(), t.as_secs(), t.subsec_millis());
+    Ok(index)
+}
+
+/// run_experiment evaluates the predictions of the test table with the training set index built as the options,
+/// or with the approximate index if any, and returns the report of the precisions and the other measurements.
+pub fn run_experiment(opts: &ExperimentOptions) -> Result<ExperimentReport, ExperimentError> {
+    opts.validate()?;
+    if opts.split && opts.use_cache {
+        warn!("ignored --cache, because the split-matrix format is not cached");
+    }
+    let use_cache = opts.use_cache && !opts.split;
+    let maxK = opts.Ks.iter().cloned().max().unwrap_or(0);
+    let prune_method = opts.prune_method();
+
+    // The training table is not read if the prebuilt index is given.
+    let train_ds;
+    let mut train_index = match opts.load_index {
+        Some(ref index_path) => {
+            train_ds = None;
+            load_index(index_path, opts.shard_size)?
+        },
+        None => {
+            // In the split-matrix format, the paths are of the feature matrices.
+            let train_ds_path = match opts.train_path {
+                Some(ref path) => path.clone(),
+                None => opts.table_path(if opts.split { "trn_X_Xf.txt" } else { "train.txt" })?,
+            };
+            info!("reading training table from {:?}", train_ds_path);
+            let ds = if opts.split {
+                let labels_path = opts.table_path("trn_X_Y.txt")?;
+                dataset_or_error(read_split_dataset(&train_ds_path, &labels_path, &opts.read_opts), &train_ds_path)?
+            } else {
+                read_dataset(&train_ds_path, &opts.read_opts, use_cache)?
+            };
+            info!("read training table with {} entries", ds.size());
+            train_ds = Some(ds);
+            info!("constructing training set index with {} threads ...", opts.nthreads);
+            let start_time = Instant::now();
+            let index_opts = IndexOptions{
+                nthreads: opts.nthreads,
+                compress: opts.compress,
+                ..IndexOptions::default()
+            };
+            let index = ShardedIndex::new(train_ds.as_ref().unwrap(), opts.shard_size, &index_opts);
+            let t = start_time.elapsed();
+            info!("finished training set index construction with {} shards in {}.{:03}s", index.nshards(), t.as_secs(), t.subsec_millis());
+            index
+        },
+    };
+    if opts.compress {
+        // The loaded index is compressed here, because the binary stores the plain posting lists.
+        train_index.compress();
+    }
+    info!("training set index uses {} bytes for the posting lists", train_index.memory_size());
+    train_index.set_scan_strategy(opts.scan_strategy);
+
+    let test_ds_path = match opts.test_path {
+        Some(ref path) => path.clone(),
+        None => opts.table_path(if opts.split { "tst_X_Xf.txt" } else { "test.txt" })?,
+    };
+    let test_opts = TestOptions{
+        Ks: &opts.Ks,
+        maxK,
+        S: opts.S,
+        alpha: opts.alpha,
+        beta: opts.beta,
+        N: opts.N,
+        nthreads: opts.nthreads,
+        use_cache,
+        read_opts: &opts.read_opts,
+        test_ds_path: &test_ds_path,
+        labels_path: if opts.split { Some(opts.table_path("tst_X_Y.txt")?) } else { None },
+    };
+    // The whole validation table is used regardless of N.
+    let validation_opts = opts.validation_path.as_ref().map(|path| {
+        TestOptions{
+            N: usize::MAX,
+            test_ds_path: path,
+            labels_path: None,
+            ..test_opts
+        }
+    });
+
+    // The pruned or quantized index is compared with the original index on the validation table, whose precisions are evaluated at first.
+    let baseline = match (prune_method.is_some(), opts.weights != WeightEncoding::F32) {
+        (true, true) => "unpruned f32",
+        (true, false) => "unpruned",
+        _ => "f32",
+    };
+    let compared = prune_method.is_some() || opts.weights != WeightEncoding::F32;
+    let baseline_size = train_index.memory_size();
+    let mut baseline_acc = None;
+    if compared {
+        match validation_opts {
+            Some(ref validation_opts) => {
+                info!("evaluating the {} index on the validation table {:?} for the comparison ...", baseline, validation_opts.test_ds_path);
+                baseline_acc = Some(test(&train_index, validation_opts, 0)?.0);
+            },
+            None => warn!("skipped the Precision@K comparison with the {} index, because --validation is not given", baseline),
+        }
+    }
+    let mut prune_summary = None;
+    if let Some(method) = prune_method {
+        let summary = match opts.memory_budget {
+            Some(budget) => train_index.prune_to_budget(method, budget).ok_or(ExperimentError::Budget(budget))?,
+            None => train_index.prune(method, opts.prune_level.unwrap()),
+        };
+        info!("pruned the postings by {:?} at level {} with {} bytes for the posting lists", method, summary.level, summary.memory_size_after);
+        prune_summary = Some(summary);
+    }
+    if let Some(ref index_path) = opts.save_index {
+        let result = if opts.shard_size > 0 || train_index.nshards() > 1 {
+            write_shards(index_path, &train_index)
+        } else {
+            write_index_binary(index_path, &train_index.shards()[0])
+        };
+        result.map_err(|e| { ExperimentError::Save(index_path.clone(), e) })?;
+        info!("saved training set index to {:?}", index_path);
+    }
+    if opts.weights != WeightEncoding::F32 {
+        train_index.quantize(opts.weights);
+        info!("quantized the weights into {:?} with {} bytes for the posting lists", opts.weights, train_index.memory_size());
+    }
+
+    // The MinHash, LabelNear and HNSW indices are built from the training table, and their recalls are measured against the training set index.
+    let minhash_index = opts.minhash.map(|minhash_opts| {
+        info!("constructing MinHash index with {} bands of {} rows ...", minhash_opts.nbands, minhash_opts.nrows);
+        let start_time = Instant::now();
+        let index = MinHashIndex::new(train_ds.as_ref().unwrap(), &minhash_opts);
+        let t = start_time.elapsed();
+        info!("finished MinHash index construction with {} bytes in {}.{:03}s", index.memory_size(), t.as_secs(), t.subsec_millis());
+        index
+    });
+    let labelnear = opts.labelnear.map(|labelnear_opts| {
+        info!("constructing LabelNear index with {} tables of {} bits ...", labelnear_opts.ntables, labelnear_opts.nbits);
+        let start_time = Instant::now();
+        let index = LabelNear::new(train_ds.as_ref().unwrap(), &labelnear_opts);
+        let t = start_time.elapsed();
+        info!("finished LabelNear index construction with {} bytes in {}.{:03}s", index.memory_size(), t.as_secs(), t.subsec_millis());
+        index
+    });
+    let hnsw_index = opts.hnsw.map(|hnsw_opts| {
+        info!("constructing HNSW index with M={},efConstruction={} ...", hnsw_opts.M, hnsw_opts.ef_construction);
+        let start_time = Instant::now();
+        let index = HnswIndex::new(train_ds.as_ref().unwrap(), &hnsw_opts);
+        let t = start_time.elapsed();
+        info!("finished HNSW index construction with {} layers and {} bytes in {}.{:03}s", index.nlayers(), index.memory_size(), t.as_secs(), t.subsec_millis());
+        index
+    });
+
+    info!("starting top-{} inference of the test table {:?} with hyper-parameters S={},alpha={},beta={} in {} threads ...", maxK, test_ds_path, opts.S, opts.alpha, opts.beta, opts.nthreads);
+    let start_time = Instant::now();
+    let (acc, ntested) = match (&minhash_index, &labelnear, &hnsw_index) {
+        (Some(minhash_index), _, _) => test(minhash_index, &test_opts, opts.per)?,
+        (_, Some(labelnear), _) => test(labelnear, &test_opts, opts.per)?,
+        (_, _, Some(hnsw_index)) => test(hnsw_index, &test_opts, opts.per)?,
+        _ => test(&train_index, &test_opts, opts.per)?,
+    };
+    if ntested == 0 {
+        return Err(ExperimentError::NoTestEntries(test_ds_path.clone()));
+    }
+    let t = start_time.elapsed();
+    let t_per_entry = t/(ntested as u32);
+    info!("finished inference of {} entries in {}.{:03}s ({:.03}ms/entry)", ntested, t.as_secs(), t.subsec_millis(), (t_per_entry.subsec_nanos() as f32)/1_000_000.0f32);
+    let precisions = opts.Ks.iter().map(|&K| {
+        let (avgPK, avgMaxPK) = acc.precision(K).unwrap();
+        (K, avgPK, avgMaxPK)
+    }).collect();
+
+    let recall = match (&minhash_index, &labelnear, &hnsw_index) {
+        (Some(minhash_index), _, _) => measure_recall("MinHash", minhash_index, &train_index, minhash_index.mean_candidates(), &test_opts)?,
+        (_, Some(labelnear), _) => measure_recall("LabelNear", labelnear, &train_index, labelnear.mean_candidates(), &test_opts)?,
+        (_, _, Some(hnsw_index)) => measure_recall("HNSW", hnsw_index, &train_index, hnsw_index.mean_distances(), &test_opts)?,
+        _ => None,
+    };
+    let mut maxscore = None;
+    if opts.scan_strategy == ScanStrategy::MaxScore {
+        let stats = train_index.pruning_stats();
+        if stats.nqueries == 0 {
+            warn!("MaxScore was not used, because it requires beta=0 and the plain posting lists");
+        } else {
+            maxscore = Some(stats);
+        }
+    }
+    let comparison = if compared {
+        let mut validation = Vec::new();
+        if let (Some(baseline_acc), Some(ref validation_opts)) = (baseline_acc, validation_opts) {
+            info!("evaluating the index on the validation table {:?} for the comparison ...", validation_opts.test_ds_path);
+            let (validation_acc, _) = test(&train_index, validation_opts, 0)?;
+            validation = opts.Ks.iter().map(|&K| {
+                (K, validation_acc.precision(K).unwrap().0, baseline_acc.precision(K).unwrap().0)
+            }).collect();
+        }
+        Some(Comparison{ baseline, baseline_size, size: train_index.memory_size(), validation })
+    } else {
+        None
+    };
+    Ok(ExperimentReport{ ntested, precisions, recall, maxscore, prune_summary, comparison })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use dataset::write_dataset;
+    use testutil::{random_dataset,temp_path};
+
+    // options_of returns the options reading the written training and test tables.
+    fn options_of(name: &str, train_ds: &Dataset, test_ds: &Dataset) -> ExperimentOptions {
+        let (train_path, test_path) = (temp_path(&format!("{}-train.txt", name)), temp_path(&format!("{}-test.txt", name)));
+        write_dataset(&train_path, train_ds).unwrap();
+        write_dataset(&test_path, test_ds).unwrap();
+        ExperimentOptions{
+            nthreads: 2,
+            train_path: Some(train_path),
+            test_path: Some(test_path),
+            ..ExperimentOptions::default()
+        }
+    }
+
+    fn remove_tables(opts: &ExperimentOptions) {
+        fs::remove_file(opts.train_path.as_ref().unwrap()).unwrap();
+        fs::remove_file(opts.test_path.as_ref().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn conflicting_options_are_rejected() {
+        let conflicts = vec![
+            ExperimentOptions{ split: true, train_path: Some(PathBuf::from("train.txt")), ..ExperimentOptions::default() },
+            ExperimentOptions{ split: true, validation_path: Some(PathBuf::from("valid.txt")), ..ExperimentOptions::default() },
+            ExperimentOptions{ validation_path: Some(PathBuf::from("-")), ..ExperimentOptions::default() },
+            ExperimentOptions{ prune: Some(PruneMethod::Threshold), ..ExperimentOptions::default() },
+            ExperimentOptions{ minhash: Some(MinHashOptions::default()), hnsw: Some(HnswOptions::default()), ..ExperimentOptions::default() },
+            ExperimentOptions{ hnsw: Some(HnswOptions::default()), memory_budget: Some(1000), ..ExperimentOptions::default() },
+            ExperimentOptions{ labelnear: Some(LabelNearOptions::default()), weights: WeightEncoding::U8, ..ExperimentOptions::default() },
+        ];
+        for opts in &conflicts {
+            match opts.validate() {
+                Err(ExperimentError::Options(_)) => {},
+                result => panic!("{:?} for {:?}", result, opts),
+            }
+        }
+        assert!(ExperimentOptions{ memory_budget: Some(1000), ..ExperimentOptions::default() }.validate().is_ok());
+        match run_experiment(&ExperimentOptions::default()) {
+            Err(ExperimentError::Options(ref msg)) if msg == "specify dataset root path" => {},
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn empty_test_table_is_an_error() {
+        let opts = options_of("empty", &random_dataset(50, 20, 5, 31), &Dataset::new());
+        let limited = ExperimentOptions{ N: 0, test_path: opts.train_path.clone(), ..opts.clone() };
+        for opts in &[&opts, &limited] {
+            match run_experiment(opts) {
+                Err(ExperimentError::NoTestEntries(_)) => {},
+                result => panic!("{:?}", result),
+            }
+        }
+        remove_tables(&opts);
+    }
+
+    #[test]
+    fn report_has_the_requested_measurements() {
+        let test_ds = random_dataset(40, 20, 5, 33);
+        let opts = options_of("report", &random_dataset(200, 20, 5, 32), &test_ds);
+        let report = run_experiment(&opts).unwrap();
+        assert_eq!(report.ntested, test_ds.size());
+        assert_eq!(report.precisions.iter().map(|&(K, _, _)| { K }).collect::<Vec<_>>(), opts.Ks);
+        assert!(report.recall.is_none() && report.comparison.is_none());
+        assert!(report.to_string().starts_with("Precision@1="));
+
+        let report = run_experiment(&ExperimentOptions{ hnsw: Some(HnswOptions::default()), ..opts.clone() }).unwrap();
+        assert_eq!(report.recall.as_ref().map(|recall| { recall.name }), Some("HNSW"));
+
+        let pruned = ExperimentOptions{ prune: Some(PruneMethod::Fraction), prune_level: Some(0.5), validation_path: opts.test_path.clone(), ..opts.clone() };
+        let report = run_experiment(&pruned).unwrap();
+        let comparison = report.comparison.unwrap();
+        assert!(comparison.size < comparison.baseline_size);
+        assert_eq!(comparison.validation.len(), opts.Ks.len());
+        remove_tables(&opts);
+    }
+}
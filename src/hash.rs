@@ -1,12 +1,10 @@
 use std::hash;
+use std::ops::{Deref,DerefMut};
 
-pub struct Hasher(u64);
+pub type BuildHasher = hash::BuildHasherDefault<Hasher>;
 
-impl Default for Hasher {
-    fn default() -> Hasher {
-        Hasher(0)
-    }
-}
+#[derive(Default)]
+pub struct Hasher(u64);
 
 impl hash::Hasher for Hasher {
     fn finish(&self) -> u64 {
@@ -20,3 +18,61 @@ impl hash::Hasher for Hasher {
         *self = Hasher(h);
     }
 }
+
+/// hash_u32 returns the hash of x mixed with the finalizer of MurmurHash3.
+pub fn hash_u32(x: u32) -> u32 {
+    let mut h = (x as u64) + 1;
+    h ^= h>>16;
+    h = (h*0x85ebca6b)&0xffffffff;
+    h ^= h>>13;
+    h = (h*0xc2b2ae35)&0xffffffff;
+    (h^(h>>16)) as u32
+}
+
+/// HashMap32 is the open-addressing hash map counting the u32 keys, whose number of the buckets is a power of two.
+/// Each bucket is the pair of the key and its count, where the empty bucket has the zero count.
+/// The map never grows, so the number of the distinct keys must be less than the number of the buckets.
+pub struct HashMap32(Box<[(u32, u32)]>);
+
+impl HashMap32 {
+    /// new returns the empty map of n buckets, where n must be a power of two.
+    pub fn new(n: usize) -> HashMap32 {
+        assert!(n.is_power_of_two(), "the number of the buckets must be a power of two");
+        HashMap32(vec![(0u32, 0u32); n].into_boxed_slice())
+    }
+
+    /// inc increments the count of the key, and returns the bucket.
+    pub fn inc(&mut self, key: u32) -> (u32, u32) {
+        let mask = self.len() - 1;
+        let mut k = (hash_u32(key) as usize) & mask;
+        while self[k].1 > 0 {
+            if self[k].0 == key {
+                self[k].1 += 1;
+                return self[k]
+            }
+            k = (k + 1) & mask
+        }
+        self[k] = (key, 1);
+        self[k]
+    }
+
+    /// clear empties all the buckets, which may have been reordered through DerefMut.
+    pub fn clear(&mut self) {
+        for bucket in self.iter_mut() {
+            *bucket = (0, 0);
+        }
+    }
+}
+
+impl Deref for HashMap32 {
+    type Target = [(u32, u32)];
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for HashMap32 {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
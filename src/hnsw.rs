@@ -0,0 +1,424 @@
+#![allow(non_snake_case)]
+
+use std::cmp::{self,Reverse};
+use std::collections::BinaryHeap;
+use std::mem;
+
+use candidates::CandidateVectors;
+use dataset::{Dataset,FeatureVectors};
+use hash::hash_u32;
+use nearest::{NeighborIndex,l2norm};
+
+/// HnswOptions is the options of HnswIndex.
+/// M and ef_construction are the build-time parameters, and ef is the query-time parameter, which can be changed with HnswIndex::set_ef.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub struct HnswOptions {
+    /// M is the number of the neighbors of each node at the upper layers, which is doubled at the bottom layer.
+    pub M: usize,
+    /// ef_construction is the number of the nearest nodes searched for the neighbors of each inserted node, which must be positive.
+    pub ef_construction: usize,
+    /// ef is the number of the nearest nodes searched for each query, which is raised to S, and to 1 if S is 0.
+    pub ef: usize,
+    pub seed: u32,
+}
+
+impl Default for HnswOptions {
+    fn default() -> HnswOptions {
+        HnswOptions{
+            M: 16,
+            ef_construction: 200,
+            ef: 50,
+            seed: 0,
+        }
+    }
+}
+
+/// HnswIndex is the hierarchical navigable small world (HNSW) graph of the L2-normalized feature vectors in the training dataset,
+/// whose nodes are the same vectors as DatasetIndex, and whose similarity is the sparse dot-product.
+/// The search walks greedily from the top layer down to the bottom one, where it keeps the ef nearest nodes to be rescored with the Jaccard term of beta.
+/// The graph is navigated by the cosine similarity alone, so a neighbor raised by the Jaccard term of beta may be missed,
+/// and the entries sharing no features with the nodes around them are hardly reachable.
+pub struct HnswIndex<'a> {
+    M: usize,
+    ef: usize,
+    // dim is the number of the features of the training vectors.
+    dim: usize,
+    entries: CandidateVectors<'a>,
+    // links[i][l] is the neighbors of the node i at the layer l, so links[i].len() - 1 is the level of the node i.
+    // The entries of no features are isolated at the bottom layer.
+    links: Vec<Vec<Vec<u32>>>,
+    entry: Option<u32>,
+}
+
+// Near is the node with its similarity to the searched vector, which is ordered by the similarity and then by the reversed node id.
+#[derive(Clone,Copy,Debug)]
+struct Near {
+    sim: f32,
+    i: u32,
+}
+
+impl PartialEq for Near {
+    fn eq(&self, other: &Near) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Near {}
+
+impl PartialOrd for Near {
+    fn partial_cmp(&self, other: &Near) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Near {
+    fn cmp(&self, other: &Near) -> cmp::Ordering {
+        self.sim.total_cmp(&other.sim).then_with(|| { other.i.cmp(&self.i) })
+    }
+}
+
+/// HnswContext is the buffers used in the searches of HnswIndex.
+pub struct HnswContext {
+    // visited[i] is stamp if the node i is visited in the current search.
+    visited: Vec<u32>,
+    stamp: u32,
+    candidates: BinaryHeap<Near>,
+    results: BinaryHeap<Reverse<Near>>,
+    nears: Vec<Near>,
+    query: Vec<(u32, f32)>,
+    // dense is the scattered vector searched in search_layer, and neighbor is the one used in select_neighbors.
+    dense: DenseVector,
+    neighbor: DenseVector,
+    index_sims: Vec<(u32, f32)>,
+    ndistances: usize,
+}
+
+impl HnswContext {
+    fn new(n: usize, dim: usize) -> HnswContext {
+        HnswContext{
+            visited: vec![0; n],
+            stamp: 0,
+            candidates: BinaryHeap::new(),
+            results: BinaryHeap::new(),
+            nears: Vec::new(),
+            query: Vec::new(),
+            dense: DenseVector::new(dim),
+            neighbor: DenseVector::new(dim),
+            index_sims: Vec::new(),
+            ndistances: 0,
+        }
+    }
+
+    // next_stamp starts a new search.
+    fn next_stamp(&mut self) {
+        if self.stamp == u32::MAX {
+            for visited in &mut self.visited {
+                *visited = 0;
+            }
+            self.stamp = 0;
+        }
+        self.stamp += 1;
+    }
+}
+
+// DenseVector is the dense copy of a sparse vector for computing its dot-products with many sparse vectors.
+// The features out of the dimension are ignored, because no training vector has them.
+struct DenseVector(Vec<f32>);
+
+impl DenseVector {
+    fn new(dim: usize) -> DenseVector {
+        DenseVector(vec![0.0; dim])
+    }
+
+    fn scatter(&mut self, v: &[(u32, f32)]) {
+        for &(key, value) in v {
+            if let Some(x) = self.0.get_mut(key as usize) {
+                *x += value;
+            }
+        }
+    }
+
+    // clear clears the scattered vector v.
+    fn clear(&mut self, v: &[(u32, f32)]) {
+        for &(key, _) in v {
+            if let Some(x) = self.0.get_mut(key as usize) {
+                *x = 0.0;
+            }
+        }
+    }
+
+    // dot returns the dot-product with the training vector v.
+    fn dot(&self, v: &[(u32, f32)]) -> f32 {
+        let mut sim = 0.0f32;
+        for &(key, value) in v {
+            sim += self.0[key as usize]*value;
+        }
+        sim
+    }
+}
+
+// search_layer fills ctx.nears with the ef nearest nodes to the vector scattered in ctx.dense at the layer, searched from the nodes in ctx.nears,
+// in the descending order of the similarities.
+fn search_layer(vectors: &FeatureVectors, links: &[Vec<Vec<u32>>], ef: usize, layer: usize, ctx: &mut HnswContext) {
+    ctx.next_stamp();
+    for &near in &ctx.nears {
+        ctx.visited[near.i as usize] = ctx.stamp;
+        ctx.candidates.push(near);
+        ctx.results.push(Reverse(near));
+    }
+    while ctx.results.len() > ef {
+        ctx.results.pop();
+    }
+    while let Some(near) = ctx.candidates.pop() {
+        let worst = ctx.results.peek().unwrap().0;
+        if near < worst {
+            break;
+        }
+        for &j in &links[near.i as usize][layer] {
+            if ctx.visited[j as usize] == ctx.stamp {
+                continue;
+            }
+            ctx.visited[j as usize] = ctx.stamp;
+            ctx.ndistances += 1;
+            let next = Near{ sim: ctx.dense.dot(&vectors[j as usize]), i: j };
+            if ctx.results.len() < ef || next > ctx.results.peek().unwrap().0 {
+                ctx.candidates.push(next);
+                ctx.results.push(Reverse(next));
+                if ctx.results.len() > ef {
+                    ctx.results.pop();
+                }
+            }
+        }
+    }
+    ctx.candidates.clear();
+    ctx.nears.clear();
+    ctx.nears.extend(ctx.results.drain().map(|Reverse(near)| { near }));
+    ctx.nears.sort_by(|a, b| { b.cmp(a) });
+}
+
+// select_neighbors returns at most M neighbors from the nears to the node in the descending order of the similarities with the heuristic of HNSW.
+// The near is selected if it is more similar to the node than to any selected one, so that the neighbors spread in the different directions.
+// The rest are filled with the discarded nears in the order of the similarities, because the sparse vectors are often orthogonal.
+fn select_neighbors(vectors: &FeatureVectors, nears: &[Near], M: usize, dense: &mut DenseVector) -> Vec<u32> {
+    let mut neighbors: Vec<u32> = Vec::with_capacity(M);
+    let mut discarded = Vec::new();
+    for near in nears {
+        if neighbors.len() >= M {
+            break;
+        }
+        let v = &vectors[near.i as usize];
+        dense.scatter(v);
+        if neighbors.iter().all(|&j| { dense.dot(&vectors[j as usize]) < near.sim }) {
+            neighbors.push(near.i);
+        } else {
+            discarded.push(near.i);
+        }
+        dense.clear(v);
+    }
+    for i in discarded {
+        if neighbors.len() >= M {
+            break;
+        }
+        neighbors.push(i);
+    }
+    neighbors
+}
+
+impl<'a> HnswIndex<'a> {
+    pub fn new(ds: &'a Dataset, opts: &HnswOptions) -> HnswIndex<'a> {
+        if opts.M < 2 {
+            panic!("M must be at least 2: {}", opts.M);
+        }
+        if opts.ef_construction == 0 {
+            panic!("ef_construction must be positive: {}", opts.ef_construction);
+        }
+        let entries = CandidateVectors::new(ds);
+        let dim = entries.vectors().data().iter().map(|&(key, _)| { key as usize + 1 }).max().unwrap_or(0);
+        let mut index = HnswIndex{
+            M: opts.M,
+            ef: opts.ef,
+            dim,
+            links: Vec::with_capacity(entries.size()),
+            entries,
+            entry: None,
+        };
+        // The levels are drawn from the geometric distribution of the ratio 1/M.
+        let mL = 1.0/(opts.M as f64).ln();
+        let seed = hash_u32(opts.seed);
+        let mut ctx = HnswContext::new(index.size(), dim);
+        for i in 0..index.size() {
+            let u = ((hash_u32(seed ^ (i as u32)) as f64) + 1.0)/4294967296.0;
+            let level = (-u.ln()*mL) as usize;
+            index.insert(level, opts.ef_construction, &mut ctx);
+        }
+        index
+    }
+
+    // insert inserts the next node at the level.
+    fn insert(&mut self, level: usize, ef_construction: usize, ctx: &mut HnswContext) {
+        let i = self.links.len() as u32;
+        let q = &self.entries.vectors()[i as usize];
+        if q.is_empty() {
+            self.links.push(vec![Vec::new()]);
+            return;
+        }
+        self.links.push(vec![Vec::new(); level + 1]);
+        let entry = match self.entry {
+            Some(entry) => entry,
+            None => {
+                self.entry = Some(i);
+                return;
+            },
+        };
+        let top = self.links[entry as usize].len() - 1;
+        ctx.dense.scatter(q);
+        ctx.nears.clear();
+        ctx.nears.push(Near{ sim: ctx.dense.dot(&self.entries.vectors()[entry as usize]), i: entry });
+        for layer in (level+1..=top).rev() {
+            search_layer(self.entries.vectors(), &self.links, 1, layer, ctx);
+        }
+        for layer in (0..=level.min(top)).rev() {
+            search_layer(self.entries.vectors(), &self.links, ef_construction, layer, ctx);
+            let neighbors = select_neighbors(self.entries.vectors(), &ctx.nears, self.M, &mut ctx.neighbor);
+            let maxM = self.max_neighbors(layer);
+            for &j in &neighbors {
+                self.links[j as usize][layer].push(i);
+                if self.links[j as usize][layer].len() > maxM {
+                    // The neighbors exceeding the limit are reselected with the heuristic.
+                    let v = &self.entries.vectors()[j as usize];
+                    ctx.neighbor.scatter(v);
+                    let mut nears: Vec<_> = self.links[j as usize][layer].iter().map(|&k| {
+                        Near{ sim: ctx.neighbor.dot(&self.entries.vectors()[k as usize]), i: k }
+                    }).collect();
+                    ctx.neighbor.clear(v);
+                    nears.sort_by(|a, b| { b.cmp(a) });
+                    self.links[j as usize][layer] = select_neighbors(self.entries.vectors(), &nears, maxM, &mut ctx.neighbor);
+                }
+            }
+            self.links[i as usize][layer] = neighbors;
+        }
+        ctx.dense.clear(q);
+        if level > top {
+            self.entry = Some(i);
+        }
+    }
+
+    // max_neighbors returns the maximum number of the neighbors at the layer.
+    fn max_neighbors(&self, layer: usize) -> usize {
+        if layer == 0 { 2*self.M } else { self.M }
+    }
+
+    pub fn size(&self) -> usize {
+        self.entries.size()
+    }
+
+    /// nlayers returns the number of the layers of the graph.
+    pub fn nlayers(&self) -> usize {
+        self.entry.map(|entry| { self.links[entry as usize].len() }).unwrap_or(0)
+    }
+
+    /// ef returns the number of the nearest nodes searched for each query.
+    pub fn ef(&self) -> usize {
+        self.ef
+    }
+
+    pub fn set_ef(&mut self, ef: usize) {
+        self.ef = ef;
+    }
+
+    /// mean_distances returns the mean number of the similarities computed per query.
+    pub fn mean_distances(&self) -> f32 {
+        self.entries.mean_candidates()
+    }
+
+    /// memory_size returns the number of the bytes used by the vectors and the graph.
+    pub fn memory_size(&self) -> usize {
+        let mut size = self.entries.memory_size();
+        for layers in &self.links {
+            size += mem::size_of_val(&layers[..]);
+            size += layers.iter().map(|neighbors| { mem::size_of_val(&neighbors[..]) }).sum::<usize>();
+        }
+        size
+    }
+}
+
+impl<'a> NeighborIndex for HnswIndex<'a> {
+    type Context = HnswContext;
+
+    fn new_context(&self) -> HnswContext {
+        HnswContext::new(self.size(), self.dim)
+    }
+
+    fn find_nearests(&self, xi: &[(u32, f32)], S: usize, beta: f32, ctx: &mut HnswContext) -> Vec<(u32, f32)> {
+        let entry = match self.entry {
+            Some(entry) if !xi.is_empty() => entry,
+            _ => {
+                self.entries.count_query(0);
+                return Vec::new();
+            },
+        };
+        let xinorm = l2norm(xi);
+        ctx.query.clear();
+        ctx.query.extend(xi.iter().map(|&(key, value)| { (key, value/xinorm) }));
+        ctx.dense.scatter(&ctx.query);
+        ctx.ndistances = 1;
+        ctx.nears.clear();
+        ctx.nears.push(Near{ sim: ctx.dense.dot(&self.entries.vectors()[entry as usize]), i: entry });
+        for layer in (1..self.links[entry as usize].len()).rev() {
+            search_layer(self.entries.vectors(), &self.links, 1, layer, ctx);
+        }
+        search_layer(self.entries.vectors(), &self.links, self.ef.max(S).max(1), 0, ctx);
+        ctx.dense.clear(&ctx.query);
+        self.entries.count_query(ctx.ndistances);
+        self.entries.rescore_top_k(ctx.nears.iter().map(|near| { near.i }), xi, S, beta, &mut ctx.index_sims)
+    }
+
+    fn labels(&self, i: u32) -> &[u32] {
+        self.entries.labels(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{random_dataset,recall};
+
+    #[test]
+    #[should_panic(expected = "ef_construction must be positive")]
+    fn zero_ef_construction_is_rejected() {
+        let ds = random_dataset(20, 10, 5, 9);
+        HnswIndex::new(&ds, &HnswOptions{ ef_construction: 0, ..HnswOptions::default() });
+    }
+
+    #[test]
+    fn zero_ef_is_raised() {
+        let ds = random_dataset(100, 10, 5, 10);
+        let queries = random_dataset(20, 10, 5, 11);
+        let index = HnswIndex::new(&ds, &HnswOptions{ ef: 0, ..HnswOptions::default() });
+        let mut ctx = index.new_context();
+        for (xi, _) in &queries {
+            assert!(index.find_nearests(xi, 0, 0.5, &mut ctx).is_empty());
+            assert!(index.find_nearests(xi, 3, 0.5, &mut ctx).len() <= 3);
+        }
+    }
+
+    #[test]
+    fn default_options_find_the_neighbors() {
+        let ds = random_dataset(500, 40, 10, 21);
+        let queries = random_dataset(100, 40, 10, 22);
+        let index = HnswIndex::new(&ds, &HnswOptions::default());
+        for &beta in &[0.0, 0.5] {
+            assert!(recall(&index, &ds, &queries, 10, beta) >= 0.95, "beta={}", beta);
+        }
+    }
+
+    #[test]
+    fn sparse_graph_of_small_ef_misses_neighbors() {
+        let ds = random_dataset(500, 40, 10, 21);
+        let queries = random_dataset(100, 40, 10, 22);
+        let sparse = HnswIndex::new(&ds, &HnswOptions{ M: 2, ef: 1, ..HnswOptions::default() });
+        let dense = HnswIndex::new(&ds, &HnswOptions::default());
+        assert!(recall(&sparse, &ds, &queries, 10, 0.5) < recall(&dense, &ds, &queries, 10, 0.5));
+        assert!(sparse.mean_distances() < dense.mean_distances());
+    }
+}
@@ -0,0 +1,186 @@
+#![allow(non_snake_case)]
+
+use std::collections::HashMap;
+
+use candidates::{CandidateVectors,tables_size};
+use dataset::Dataset;
+use hash::{BuildHasher,HashMap32,hash_u32};
+use nearest::NeighborIndex;
+use topk::sort_largests_with_heap;
+
+/// LabelNearOptions is the options of LabelNear.
+/// Each of ntables hash tables hashes the entries with nbits random hyperplanes (at most 32).
+/// The rescore*S entries colliding with the query in the most tables are rescored for the top-S neighbors.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub struct LabelNearOptions {
+    pub nbits: usize,
+    pub ntables: usize,
+    pub rescore: usize,
+    pub seed: u32,
+}
+
+impl Default for LabelNearOptions {
+    fn default() -> LabelNearOptions {
+        LabelNearOptions{
+            nbits: 16,
+            ntables: 16,
+            rescore: 2,
+            seed: 0,
+        }
+    }
+}
+
+/// LabelNear is the approximate nearest neighbor label model with the SimHash (random-hyperplane LSH) tables, ported from sticker's LabelNear.
+/// Two vectors collide in a table with the probability of about (1 - θ/π)^nbits for the angle θ between them, so the weights matter unlike MinHashIndex.
+/// The entries are counted up with HashMap32 for each table whose bucket contains the query,
+/// and only the rescore*S most colliding ones are rescored, so a neighbor colliding in few tables is missed even if it is the nearest.
+pub struct LabelNear<'a> {
+    nbits: usize,
+    rescore: usize,
+    // seeds is the seed of the hyperplanes of each table.
+    seeds: Vec<u32>,
+    entries: CandidateVectors<'a>,
+    tables: Vec<HashMap<u32, Vec<u32>, BuildHasher>>,
+}
+
+/// LabelNearContext is the buffers used in LabelNear::find_nearests.
+pub struct LabelNearContext {
+    counts: HashMap32,
+    index_sims: Vec<(u32, f32)>,
+}
+
+// simhash returns the signs of the projections of xi onto the nbits random hyperplanes of the seed.
+// The j-th coordinate of the b-th hyperplane is +1 if the b-th bit of the hash of the feature j is set, and -1 otherwise.
+fn simhash(seed: u32, nbits: usize, xi: &[(u32, f32)]) -> u32 {
+    let mut projections = [0.0f32; 32];
+    for &(key, value) in xi {
+        let bits = hash_u32(key ^ seed);
+        for (b, projection) in projections[..nbits].iter_mut().enumerate() {
+            if (bits>>b)&1 == 1 {
+                *projection += value;
+            } else {
+                *projection -= value;
+            }
+        }
+    }
+    projections[..nbits].iter().enumerate().fold(0, |h, (b, &projection)| {
+        if projection > 0.0 { h | (1<<b) } else { h }
+    })
+}
+
+impl<'a> LabelNear<'a> {
+    pub fn new(ds: &'a Dataset, opts: &LabelNearOptions) -> LabelNear<'a> {
+        if opts.nbits == 0 || opts.nbits > 32 {
+            panic!("the number of the hash bits must be in 1..=32: {}", opts.nbits);
+        }
+        let seeds: Vec<_> = (0..opts.ntables as u32).map(|t| { hash_u32(opts.seed ^ hash_u32(t)) }).collect();
+        let entries = CandidateVectors::new(ds);
+        let mut tables: Vec<_> = (0..opts.ntables).map(|_| { HashMap::default() }).collect();
+        for (i, xi) in entries.vectors().iter().enumerate() {
+            if xi.is_empty() {
+                continue;
+            }
+            for (table, &seed) in tables.iter_mut().zip(&seeds) {
+                table.entry(simhash(seed, opts.nbits, xi)).or_insert_with(Vec::new).push(i as u32);
+            }
+        }
+        LabelNear{
+            nbits: opts.nbits,
+            rescore: opts.rescore,
+            seeds,
+            entries,
+            tables,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.entries.size()
+    }
+
+    /// nbits returns the number of the hash bits of each table.
+    pub fn nbits(&self) -> usize {
+        self.nbits
+    }
+
+    /// ntables returns the number of the hash tables.
+    pub fn ntables(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// mean_candidates returns the mean number of the entries colliding with the query per query.
+    pub fn mean_candidates(&self) -> f32 {
+        self.entries.mean_candidates()
+    }
+
+    /// memory_size returns the number of the bytes used by the vectors and the hash tables.
+    pub fn memory_size(&self) -> usize {
+        self.entries.memory_size() + tables_size(&self.tables)
+    }
+}
+
+impl<'a> NeighborIndex for LabelNear<'a> {
+    type Context = LabelNearContext;
+
+    fn new_context(&self) -> LabelNearContext {
+        LabelNearContext{
+            counts: HashMap32::new(1),
+            index_sims: Vec::new(),
+        }
+    }
+
+    fn find_nearests(&self, xi: &[(u32, f32)], S: usize, beta: f32, ctx: &mut LabelNearContext) -> Vec<(u32, f32)> {
+        let buckets: Vec<_> = self.tables.iter().zip(&self.seeds).filter_map(|(table, &seed)| {
+            table.get(&simhash(seed, self.nbits, xi))
+        }).collect();
+        // The map is kept at most 4 times larger than needed, because it is sorted and cleared for each query.
+        let nbuckets = (2*buckets.iter().map(|entries| { entries.len() }).sum::<usize>()).next_power_of_two();
+        if ctx.counts.len() < nbuckets || ctx.counts.len() > 4*nbuckets {
+            ctx.counts = HashMap32::new(nbuckets);
+        }
+        let mut ncandidates = 0;
+        for entries in buckets {
+            for &i in entries {
+                if ctx.counts.inc(i).1 == 1 {
+                    ncandidates += 1;
+                }
+            }
+        }
+        self.entries.count_query(ncandidates);
+        let nrescored = ncandidates.min(self.rescore*S);
+        sort_largests_with_heap(&mut ctx.counts, nrescored, |a, b| { a.1 > b.1 || (a.1 == b.1 && a.0 < b.0) });
+        let index_sims = self.entries.rescore_top_k(ctx.counts[..nrescored].iter().map(|&(i, _)| { i }), xi, S, beta, &mut ctx.index_sims);
+        ctx.counts.clear();
+        index_sims
+    }
+
+    fn labels(&self, i: u32) -> &[u32] {
+        self.entries.labels(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{random_dataset,recall};
+
+    fn labelnear_recall(nbits: usize, ntables: usize, rescore: usize) -> f32 {
+        let ds = random_dataset(500, 40, 10, 21);
+        let queries = random_dataset(100, 40, 10, 22);
+        let index = LabelNear::new(&ds, &LabelNearOptions{ nbits, ntables, rescore, ..LabelNearOptions::default() });
+        recall(&index, &ds, &queries, 10, 0.5)
+    }
+
+    #[test]
+    fn more_tables_of_fewer_bits_raise_recall() {
+        let (single, default, wide) = (labelnear_recall(16, 1, 1), labelnear_recall(16, 16, 2), labelnear_recall(2, 32, 8));
+        assert!(single < default && default < wide, "{} {} {}", single, default, wide);
+        assert!(wide >= 0.8);
+    }
+
+    #[test]
+    #[should_panic(expected = "the number of the hash bits must be in 1..=32")]
+    fn too_many_bits_are_rejected() {
+        let ds = random_dataset(20, 10, 5, 9);
+        LabelNear::new(&ds, &LabelNearOptions{ nbits: 33, ..LabelNearOptions::default() });
+    }
+}
@@ -1,2 +1,20 @@
-pub mod hash;
+extern crate flate2;
+extern crate memmap2;
+extern crate zstd;
+
 #[macro_use] pub mod logger;
+pub mod cache;
+pub mod candidates;
+pub mod csr;
+pub mod dataset;
+pub mod experiment;
+pub mod hash;
+pub mod hnsw;
+pub mod labelnear;
+pub mod minhash;
+pub mod nearest;
+pub mod postings;
+pub mod shard;
+pub mod split;
+#[cfg(test)] mod testutil;
+pub mod topk;
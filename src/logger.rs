@@ -1,4 +1,6 @@
 use std::env;
+use std::error;
+use std::fmt;
 use std::str::FromStr;
 
 extern crate time;
@@ -25,10 +27,17 @@ impl FromStr for Level {
     }
 }
 
-// TODO: Consider the way to implement ParseLevelError as a normal error handler.
 #[derive(Debug)]
 pub struct ParseLevelError(String);
 
+impl fmt::Display for ParseLevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for ParseLevelError {}
+
 pub fn log(level: Level, msg: &str) {
     let min_level = env::var("RUST_LOG").unwrap_or("WARN".to_string()).parse::<Level>().expect("illegal LOGLEVEL");
     if level >= min_level {
@@ -0,0 +1,180 @@
+#![allow(non_snake_case)]
+
+use std::collections::HashMap;
+
+use candidates::{CandidateVectors,tables_size};
+use dataset::Dataset;
+use hash::{BuildHasher,hash_u32};
+use nearest::NeighborIndex;
+
+/// MinHashOptions is the options of MinHashIndex.
+/// The signature of each entry consists of nbands bands of nrows MinHash values.
+/// The entries sharing any band with the query are the candidates,
+/// so more bands raise the recall, and more rows lower the number of the candidates.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub struct MinHashOptions {
+    pub nbands: usize,
+    pub nrows: usize,
+    pub seed: u32,
+}
+
+impl Default for MinHashOptions {
+    fn default() -> MinHashOptions {
+        MinHashOptions{
+            nbands: 16,
+            nrows: 2,
+            seed: 0,
+        }
+    }
+}
+
+/// MinHashIndex is the approximate index finding the candidates with the banded LSH tables of the MinHash signatures of the feature sets.
+/// Two entries share a band with the probability of their Jaccard similarity of the feature sets to the power of nrows,
+/// so the index favors the neighbors of the many common features regardless of the weights.
+pub struct MinHashIndex<'a> {
+    seeds: Vec<u32>,
+    nrows: usize,
+    entries: CandidateVectors<'a>,
+    // tables maps the hash of each band to the entries.
+    tables: Vec<HashMap<u64, Vec<u32>, BuildHasher>>,
+}
+
+/// MinHashContext is the buffers used in MinHashIndex::find_nearests.
+pub struct MinHashContext {
+    seen: Vec<bool>,
+    candidates: Vec<u32>,
+    index_sims: Vec<(u32, f32)>,
+    signature: Vec<u32>,
+}
+
+// band_hash returns the hash of the band of the MinHash values with FNV-1a.
+fn band_hash(band: usize, values: &[u32]) -> u64 {
+    let mut h = 0xcbf29ce484222325u64 ^ (band as u64);
+    for &value in values {
+        h = (h ^ (value as u64)).wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+impl<'a> MinHashIndex<'a> {
+    pub fn new(ds: &'a Dataset, opts: &MinHashOptions) -> MinHashIndex<'a> {
+        let nhashes = opts.nbands*opts.nrows;
+        let seeds: Vec<_> = (0..nhashes as u32).map(|j| { hash_u32(opts.seed ^ hash_u32(j)) }).collect();
+        let entries = CandidateVectors::new(ds);
+        let mut tables: Vec<_> = (0..opts.nbands).map(|_| { HashMap::default() }).collect();
+        let mut signature = vec![0; nhashes];
+        for (i, xi) in entries.vectors().iter().enumerate() {
+            if xi.is_empty() {
+                continue;
+            }
+            minhash_signature(&seeds, xi, &mut signature);
+            for (band, table) in tables.iter_mut().enumerate() {
+                let h = band_hash(band, &signature[band*opts.nrows..(band+1)*opts.nrows]);
+                table.entry(h).or_insert_with(Vec::new).push(i as u32);
+            }
+        }
+        MinHashIndex{
+            seeds,
+            nrows: opts.nrows,
+            entries,
+            tables,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.entries.size()
+    }
+
+    /// nbands returns the number of the bands in the signatures.
+    pub fn nbands(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// nrows returns the number of the MinHash values in each band.
+    pub fn nrows(&self) -> usize {
+        self.nrows
+    }
+
+    /// mean_candidates returns the mean number of the candidates rescored per query.
+    pub fn mean_candidates(&self) -> f32 {
+        self.entries.mean_candidates()
+    }
+
+    /// memory_size returns the number of the bytes used by the vectors and the LSH tables.
+    pub fn memory_size(&self) -> usize {
+        self.entries.memory_size() + tables_size(&self.tables)
+    }
+}
+
+// minhash_signature fills signature with the minimum hash of the features of the non-empty xi for each seed.
+fn minhash_signature(seeds: &[u32], xi: &[(u32, f32)], signature: &mut [u32]) {
+    for (value, &seed) in signature.iter_mut().zip(seeds) {
+        *value = xi.iter().map(|&(key, _)| { hash_u32(key ^ seed) }).min().unwrap();
+    }
+}
+
+impl<'a> NeighborIndex for MinHashIndex<'a> {
+    type Context = MinHashContext;
+
+    fn new_context(&self) -> MinHashContext {
+        MinHashContext{
+            seen: vec![false; self.entries.size()],
+            candidates: Vec::new(),
+            index_sims: Vec::new(),
+            signature: vec![0; self.seeds.len()],
+        }
+    }
+
+    fn find_nearests(&self, xi: &[(u32, f32)], S: usize, beta: f32, ctx: &mut MinHashContext) -> Vec<(u32, f32)> {
+        if xi.is_empty() {
+            self.entries.count_query(0);
+            return Vec::new();
+        }
+        minhash_signature(&self.seeds, xi, &mut ctx.signature);
+        for (band, table) in self.tables.iter().enumerate() {
+            let h = band_hash(band, &ctx.signature[band*self.nrows..(band+1)*self.nrows]);
+            if let Some(entries) = table.get(&h) {
+                for &i in entries {
+                    if !ctx.seen[i as usize] {
+                        ctx.seen[i as usize] = true;
+                        ctx.candidates.push(i);
+                    }
+                }
+            }
+        }
+        self.entries.count_query(ctx.candidates.len());
+        for &i in &ctx.candidates {
+            ctx.seen[i as usize] = false;
+        }
+        self.entries.rescore_top_k(ctx.candidates.drain(..), xi, S, beta, &mut ctx.index_sims)
+    }
+
+    fn labels(&self, i: u32) -> &[u32] {
+        self.entries.labels(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{random_dataset,recall};
+
+    fn minhash_recall(nbands: usize, nrows: usize) -> f32 {
+        let ds = random_dataset(500, 40, 10, 21);
+        let queries = random_dataset(100, 40, 10, 22);
+        let index = MinHashIndex::new(&ds, &MinHashOptions{ nbands, nrows, ..MinHashOptions::default() });
+        recall(&index, &ds, &queries, 10, 0.5)
+    }
+
+    #[test]
+    fn default_options_find_most_neighbors() {
+        assert!(minhash_recall(16, 2) >= 0.7);
+    }
+
+    #[test]
+    fn more_bands_of_fewer_rows_raise_recall() {
+        let (narrow, default, wide) = (minhash_recall(1, 8), minhash_recall(16, 2), minhash_recall(64, 1));
+        assert!(narrow < default && default < wide, "{} {} {}", narrow, default, wide);
+        assert_eq!(wide, 1.0);
+    }
+}
@@ -0,0 +1,1430 @@
+#![allow(non_snake_case)]
+
+use std::borrow::Cow;
+use std::collections::{HashMap,HashSet};
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread;
+
+use csr::Buffer;
+use dataset::{Dataset,FeatureVectors,LabelVector,LabelVectors};
+use hash::BuildHasher;
+use postings::{CsrPostings,PostingIndex,PostingList,WeightEncoding};
+use topk::{ranks_before,sort_largests_with_heap,top_k};
+
+/// l2norm returns the L2-norm of the given feature vector.
+pub fn l2norm(xi: &[(u32, f32)]) -> f32 {
+    let mut xinorm = 0.0f32;
+    for &(_, value) in xi {
+        xinorm += value*value;
+    }
+    xinorm.sqrt()
+}
+
+/// DatasetIndex is the inverted index of the L2-normalized feature vectors in the training dataset.
+/// The index either borrows the label vectors of the dataset, or owns them if loaded with cache::load_index_binary or made with into_owned.
+///
+/// The plain posting lists are stored in the CSR layout, and the encoded ones are stored in a hash map keyed by the features.
+///
+/// The entries can be inserted, deleted and updated incrementally.
+/// The deleted entries are only marked as tombstones, which keep their entry ids until compact is called.
+/// Likewise, the updated labels are kept aside from the label vectors until compact is called.
+pub struct DatasetIndex<'a> {
+    nfeatures_list: Buffer<u32>,
+    indices: PostingIndex,
+    labelvecs: Cow<'a, LabelVectors>,
+    // relabeled is the labels replaced by update_labels, which override labelvecs until compact.
+    relabeled: HashMap<u32, LabelVector, BuildHasher>,
+    // compress and weights are the representation of the posting lists of the new keys made by insert.
+    compress: bool,
+    weights: WeightEncoding,
+    deleted: Vec<bool>,
+    ndeleted: usize,
+    strategy: ScanStrategy,
+    pruning: PruningCounters,
+}
+
+// index_entries returns the posting lists of the entries from start in ds, filling the number of the features of each entry.
+// The entry ids in the posting lists are counted from first.
+fn index_entries(ds: &Dataset, first: usize, start: usize, nfeatures_list: &mut [u32]) -> HashMap<u32, Vec<(u32, f32)>, BuildHasher> {
+    let mut indices: HashMap<u32, Vec<(u32, f32)>, BuildHasher> = HashMap::default();
+    for (k, nfeatures) in nfeatures_list.iter_mut().enumerate() {
+        let i = start + k;
+        let xi = &ds.X[i];
+        let xinorm = l2norm(xi);
+        for &(key, value) in xi {
+            indices.entry(key).or_default().push(((i - first) as u32, value/xinorm));
+        }
+        *nfeatures = xi.len() as u32;
+    }
+    indices
+}
+
+/// similarity returns the similarity of find_nearests from the dot-product psim with the unnormalized query of xilen features,
+/// and the number pcount of the features shared with the training entry of nfeatures features.
+#[inline]
+pub fn similarity(psim: f32, pcount: u32, nfeatures: u32, xilen: usize, beta: f32) -> f32 {
+    let jaccard = (pcount as f32)/((nfeatures + (xilen as u32) - pcount) as f32);
+    if beta == 0.0 {
+        psim
+    } else if beta == 1.0 {
+        jaccard*psim
+    } else {
+        jaccard.powf(beta)*psim
+    }
+}
+
+/// normalized_vectors returns the L2-normalized feature vectors of ds sorted by the features, which are the vectors indexed by DatasetIndex.
+pub fn normalized_vectors(ds: &Dataset) -> FeatureVectors {
+    let mut vectors = FeatureVectors::with_capacity(ds.size(), ds.X.nnz());
+    let mut xi = Vec::new();
+    for i in 0..ds.size() {
+        xi.clear();
+        xi.extend_from_slice(&ds.X[i]);
+        let xinorm = l2norm(&xi);
+        for entry in &mut xi {
+            entry.1 /= xinorm;
+        }
+        xi.sort_by_key(|&(key, _)| { key });
+        vectors.push(&xi);
+    }
+    vectors
+}
+
+/// rescore returns the similarity of find_nearests between the query xi and the vector from normalized_vectors, or None if the dot-product is not positive.
+/// The dot-product is accumulated in the order of the features of xi as find_nearests, so the similarity is bitwise identical to the one of find_nearests.
+pub fn rescore(vector: &[(u32, f32)], xi: &[(u32, f32)], beta: f32) -> Option<f32> {
+    let (mut psim, mut pcount) = (0.0f32, 0);
+    for &(key, value) in xi {
+        if let Ok(k) = vector.binary_search_by_key(&key, |&(key, _)| { key }) {
+            psim += value*vector[k].1;
+            pcount += 1;
+        }
+    }
+    if psim <= 0.0 {
+        return None;
+    }
+    Some(similarity(psim, pcount, vector.len() as u32, xi.len(), beta))
+}
+
+/// ScanStrategy is the strategy of find_nearests for collecting the candidates from the accumulator.
+#[derive(Clone,Copy,Debug,Default,Eq,PartialEq)]
+pub enum ScanStrategy {
+    /// Auto uses Sparse, unless the candidates are more than 1/AUTO_FULL_SCAN_RATIO of the training entries.
+    #[default]
+    Auto,
+    /// Sparse records the touched entries, so that the cost depends only on the number of the candidates.
+    Sparse,
+    /// Full scans the whole accumulator, which is faster for very dense queries.
+    Full,
+    /// MaxScore evaluates the entries in the ascending order of the ids, skipping the entries which cannot enter the top-S.
+    /// The neighbors are exactly the same as the other strategies.
+    /// This is used only if beta is 0 and the posting lists are in the CSR layout, and Auto is used otherwise.
+    MaxScore,
+}
+
+impl FromStr for ScanStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ScanStrategy::Auto),
+            "sparse" => Ok(ScanStrategy::Sparse),
+            "full" => Ok(ScanStrategy::Full),
+            "maxscore" => Ok(ScanStrategy::MaxScore),
+            _ => Err(format!("unknown scan strategy: {}", s)),
+        }
+    }
+}
+
+/// AUTO_FULL_SCAN_RATIO is the threshold of ScanStrategy::Auto.
+pub const AUTO_FULL_SCAN_RATIO: usize = 16;
+
+/// PruningStats is the numbers of the postings in the queries with ScanStrategy::MaxScore.
+#[derive(Clone,Copy,Debug,Default,Eq,PartialEq)]
+pub struct PruningStats {
+    /// The number of the queries.
+    pub nqueries: usize,
+    /// The total number of the postings of the query features.
+    pub npostings: usize,
+    /// The number of the postings never read nor passed over by the searches.
+    pub nskipped: usize,
+}
+
+// PruningCounters is PruningStats shared by the threads querying the index.
+#[derive(Debug,Default)]
+struct PruningCounters {
+    nqueries: AtomicUsize,
+    npostings: AtomicUsize,
+    nskipped: AtomicUsize,
+}
+
+// PRUNE_BISECTION_STEPS is the maximum number of the bisection steps of DatasetIndex::prune_to_budget.
+const PRUNE_BISECTION_STEPS: usize = 32;
+
+// MAXSCORE_WINDOW_SIZE is the number of the entry ids accumulated at once with ScanStrategy::MaxScore.
+const MAXSCORE_WINDOW_SIZE: usize = 4096;
+
+// seek returns the first position from k of the posting whose entry id is at least i, galloping from k.
+fn seek(postings: &[(u32, f32)], k: usize, i: u32) -> usize {
+    let mut step = 1;
+    let mut end = k;
+    while end < postings.len() && postings[end].0 < i {
+        end = k + step;
+        step *= 2;
+    }
+    let start = k + step/4;
+    let end = end.min(postings.len());
+    if start >= end {
+        return end;
+    }
+    start + postings[start..end].partition_point(|&(j, _)| { j < i })
+}
+
+/// DatasetIndexContext is the accumulator of the similarities and the feature counts used in DatasetIndex::find_nearests.
+/// A context can be reused for the queries of any index not larger than its capacity, but not shared between the threads.
+pub struct DatasetIndexContext {
+    sim_counts: Vec<(f32, u32)>,
+    touched: Vec<u32>,
+    candidates: Vec<(u32, f32)>,
+    strategy: ScanStrategy,
+}
+
+impl DatasetIndexContext {
+    /// capacity returns the number of the entries whose similarities the context can accumulate.
+    pub fn capacity(&self) -> usize {
+        self.sim_counts.len()
+    }
+}
+
+/// PruneMethod is the method of DatasetIndex::prune dropping the low-weight postings.
+#[derive(Clone,Copy,Debug,Default,Eq,PartialEq)]
+pub enum PruneMethod {
+    /// Threshold drops the postings whose absolute weights are below the level.
+    Threshold,
+    /// Fraction drops the level fraction of the postings with the smallest absolute weights in each posting list.
+    #[default]
+    Fraction,
+}
+
+impl FromStr for PruneMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "threshold" => Ok(PruneMethod::Threshold),
+            "fraction" => Ok(PruneMethod::Fraction),
+            _ => Err(format!("unknown prune method: {}", s)),
+        }
+    }
+}
+
+/// PruneSummary is the numbers of the postings and the bytes of the posting lists before and after DatasetIndex::prune.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct PruneSummary {
+    pub method: PruneMethod,
+    pub level: f32,
+    pub npostings_before: usize,
+    pub npostings_after: usize,
+    pub memory_size_before: usize,
+    pub memory_size_after: usize,
+}
+
+// PruneCutoff is the weight threshold of a posting list pruned by DatasetIndex::prune.
+// The postings whose absolute weights are above weight are kept, and so are the ones of the absolute weight equal to weight whose entry ids are at most id.
+#[derive(Clone,Copy,Debug)]
+struct PruneCutoff {
+    weight: f32,
+    id: u32,
+}
+
+impl PruneCutoff {
+    // new returns the cutoff of the postings by the method at the level.
+    // For Fraction, the postings with the smaller absolute weights are dropped first, and the ties are broken by the larger entry ids,
+    // so the cutoff is the first kept posting in this order, which is selected in buf of the length of the postings.
+    fn new(postings: &[(u32, f32)], method: PruneMethod, level: f32, buf: &mut Vec<(f32, u32)>) -> PruneCutoff {
+        match method {
+            PruneMethod::Threshold => PruneCutoff{ weight: level, id: u32::MAX },
+            PruneMethod::Fraction => {
+                let ndropped = (level.clamp(0.0, 1.0)*(postings.len() as f32)).floor() as usize;
+                if ndropped == 0 {
+                    return PruneCutoff{ weight: f32::NEG_INFINITY, id: u32::MAX };
+                }
+                if ndropped >= postings.len() {
+                    // No weight is compared equal to NaN, so nothing is kept.
+                    return PruneCutoff{ weight: f32::NAN, id: 0 };
+                }
+                buf.clear();
+                buf.extend(postings.iter().map(|&(i, v)| { (v.abs(), i) }));
+                let (_, &mut (weight, id), _) = buf.select_nth_unstable_by(ndropped, |a, b| { a.0.total_cmp(&b.0).then(b.1.cmp(&a.1)) });
+                PruneCutoff{ weight, id }
+            },
+        }
+    }
+
+    // keeps returns true if the posting is kept.
+    #[inline]
+    fn keeps(&self, i: u32, v: f32) -> bool {
+        let w = v.abs();
+        w > self.weight || (w == self.weight && i <= self.id)
+    }
+}
+
+/// IndexOptions is the options of DatasetIndex::with_options.
+#[derive(Clone,Debug,Default)]
+pub struct IndexOptions {
+    /// The number of the threads building the index, where 0 means 1.
+    pub nthreads: usize,
+    /// If true, the entry ids in the posting lists are compressed as PostingIds::Varint.
+    pub compress: bool,
+    /// The encoding of the weights in the posting lists.
+    pub weights: WeightEncoding,
+}
+
+// build_indices returns the number of the features of each entry in the range of ds, and the posting lists of the entries.
+fn build_indices(ds: &Dataset, range: Range<usize>, opts: &IndexOptions) -> (Vec<u32>, PostingIndex) {
+    let nthreads = opts.nthreads.max(1).min(range.len().max(1));
+    let chunk_size = range.len().div_ceil(nthreads).max(1);
+    let mut nfeatures_list = vec![0u32; range.len()];
+    let chunks: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = nfeatures_list.chunks_mut(chunk_size).enumerate().map(|(c, nfeatures_chunk)| {
+            let first = range.start;
+            scope.spawn(move || { index_entries(ds, first, first + c*chunk_size, nfeatures_chunk) })
+        }).collect();
+        handles.into_iter().map(|handle| { handle.join().unwrap() }).collect()
+    });
+    let mut chunks = chunks.into_iter();
+    let mut indices = chunks.next().unwrap_or_default();
+    for chunk in chunks {
+        for (key, index) in chunk {
+            indices.entry(key).or_default().extend(index);
+        }
+    }
+    let indices = if !opts.compress && opts.weights == WeightEncoding::F32 {
+        PostingIndex::Csr(CsrPostings::from_map(&indices))
+    } else {
+        PostingIndex::Map(indices.into_iter().map(|(key, index)| {
+            (key, PostingList::encode(&index, opts.compress, opts.weights))
+        }).collect())
+    };
+    (nfeatures_list, indices)
+}
+
+impl<'a> DatasetIndex<'a> {
+    pub fn new(ds: &'a Dataset) -> DatasetIndex<'a> {
+        DatasetIndex::with_threads(ds, 1)
+    }
+
+    /// with_threads is new building the index with nthreads threads.
+    pub fn with_threads(ds: &'a Dataset, nthreads: usize) -> DatasetIndex<'a> {
+        DatasetIndex::with_options(ds, &IndexOptions{ nthreads, ..IndexOptions::default() })
+    }
+
+    /// with_options is new building the index with the given options.
+    /// Each thread indexes a contiguous range of the entries, and the posting lists are concatenated in the order of the ranges.
+    /// Hence, the index is exactly the same as the one built by new except for the representation of the posting lists.
+    pub fn with_options(ds: &'a Dataset, opts: &IndexOptions) -> DatasetIndex<'a> {
+        let (nfeatures_list, indices) = build_indices(ds, 0..ds.size(), opts);
+        DatasetIndex{
+            nfeatures_list: Buffer::from(nfeatures_list),
+            indices,
+            labelvecs: Cow::Borrowed(&ds.Y),
+            relabeled: HashMap::default(),
+            compress: opts.compress,
+            weights: opts.weights,
+            deleted: vec![false; ds.size()],
+            ndeleted: 0,
+            strategy: ScanStrategy::default(),
+            pruning: PruningCounters::default(),
+        }
+    }
+
+    /// with_range is with_options building the index of the entries in the range of ds, owning their label vectors.
+    /// The entry ids of the index are counted from the start of the range.
+    pub fn with_range(ds: &Dataset, range: Range<usize>, opts: &IndexOptions) -> DatasetIndex<'static> {
+        let (nfeatures_list, indices) = build_indices(ds, range.clone(), opts);
+        let mut labelvecs = LabelVectors::with_capacity(range.len(), 0);
+        for i in range.clone() {
+            labelvecs.push(&ds.Y[i]);
+        }
+        DatasetIndex{
+            nfeatures_list: Buffer::from(nfeatures_list),
+            indices,
+            labelvecs: Cow::Owned(labelvecs),
+            relabeled: HashMap::default(),
+            compress: opts.compress,
+            weights: opts.weights,
+            deleted: vec![false; range.len()],
+            ndeleted: 0,
+            strategy: ScanStrategy::default(),
+            pruning: PruningCounters::default(),
+        }
+    }
+
+    // from_parts returns the index consisting of the given parts, which must be consistent with each other.
+    pub(crate) fn from_parts(nfeatures_list: Buffer<u32>, indices: PostingIndex, labelvecs: LabelVectors, deleted: &[u32]) -> DatasetIndex<'static> {
+        let mut index = DatasetIndex{
+            deleted: vec![false; nfeatures_list.len()],
+            nfeatures_list,
+            indices,
+            labelvecs: Cow::Owned(labelvecs),
+            relabeled: HashMap::default(),
+            compress: false,
+            weights: WeightEncoding::F32,
+            ndeleted: 0,
+            strategy: ScanStrategy::default(),
+            pruning: PruningCounters::default(),
+        };
+        for &i in deleted {
+            index.delete(i);
+        }
+        index
+    }
+
+    /// into_owned returns the index owning the label vectors, which can outlive the dataset.
+    pub fn into_owned(self) -> DatasetIndex<'static> {
+        DatasetIndex{
+            nfeatures_list: self.nfeatures_list,
+            indices: self.indices,
+            labelvecs: Cow::Owned(self.labelvecs.into_owned()),
+            relabeled: self.relabeled,
+            compress: self.compress,
+            weights: self.weights,
+            deleted: self.deleted,
+            ndeleted: self.ndeleted,
+            strategy: self.strategy,
+            pruning: self.pruning,
+        }
+    }
+
+    /// insert appends the entry to the index, and returns its entry id.
+    /// The contexts made before the insertion are too small, so new_context must be called again.
+    /// The posting lists in the CSR layout are moved into the hash map, until compact or freeze is called.
+    /// The posting lists of the new keys are encoded in the same way as the index was built, compressed or quantized.
+    pub fn insert(&mut self, xi: &[(u32, f32)], yi: &[u32]) -> u32 {
+        let i = self.nfeatures_list.len() as u32;
+        let xinorm = l2norm(xi);
+        let (compress, weights) = (self.compress, self.weights);
+        let indices = self.indices.to_mut();
+        for &(key, value) in xi {
+            indices.entry(key).or_insert_with(|| { PostingList::encode(&[], compress, weights) }).push(i, value/xinorm);
+        }
+        self.nfeatures_list.to_mut().push(xi.len() as u32);
+        self.labelvecs.to_mut().push(yi);
+        self.deleted.push(false);
+        i
+    }
+
+    /// delete marks the entry as a tombstone, which is never returned by find_nearests.
+    /// This returns false if the entry does not exist or is already deleted.
+    pub fn delete(&mut self, i: u32) -> bool {
+        match self.deleted.get_mut(i as usize) {
+            Some(deleted) if !*deleted => {
+                *deleted = true;
+                self.ndeleted += 1;
+                self.relabeled.remove(&i);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// update_labels replaces the labels of the entry with yi, which are kept aside until compact merges them into the label vectors.
+    /// This returns false if the entry does not exist or is deleted.
+    pub fn update_labels(&mut self, i: u32, yi: &[u32]) -> bool {
+        if !self.is_live(i) {
+            return false;
+        }
+        self.relabeled.insert(i, yi.to_vec());
+        true
+    }
+
+    /// labels returns the labels of the entry, reflecting update_labels.
+    #[inline]
+    pub fn labels(&self, i: u32) -> &[u32] {
+        if !self.relabeled.is_empty() {
+            if let Some(yi) = self.relabeled.get(&i) {
+                return yi;
+            }
+        }
+        &self.labelvecs[i as usize]
+    }
+
+    /// is_live returns true if the entry exists and is not deleted.
+    pub fn is_live(&self, i: u32) -> bool {
+        self.deleted.get(i as usize) == Some(&false)
+    }
+
+    /// ndeleted returns the number of the tombstones.
+    pub fn ndeleted(&self) -> usize {
+        self.ndeleted
+    }
+
+    // deleted_ids returns the entry ids of the tombstones.
+    pub(crate) fn deleted_ids(&self) -> Vec<u32> {
+        (0..self.deleted.len()).filter(|&i| { self.deleted[i] }).map(|i| { i as u32 }).collect()
+    }
+
+    /// compact removes the tombstones, renumbering the remaining entries in the same order, and merges the updated labels.
+    /// Then, the index is the same as the one freshly built from the remaining entries.
+    /// This returns the new entry id of each old entry id, which is None if deleted.
+    pub fn compact(&mut self) -> Vec<Option<u32>> {
+        let mut ids = Vec::with_capacity(self.deleted.len());
+        let mut n = 0;
+        for &deleted in &self.deleted {
+            if deleted {
+                ids.push(None);
+            } else {
+                ids.push(Some(n));
+                n += 1;
+            }
+        }
+        if self.ndeleted > 0 {
+            let indices = self.indices.to_mut();
+            for index in indices.values_mut() {
+                index.retain_map(|i| { ids[i as usize] });
+            }
+            indices.retain(|_, index| { !index.is_empty() });
+        }
+        self.freeze();
+        if self.ndeleted == 0 && self.relabeled.is_empty() {
+            return ids;
+        }
+        // The label vectors are rebuilt at once, so the updates cost linear time in total.
+        let mut nfeatures_list = Vec::with_capacity(n as usize);
+        let mut labelvecs = LabelVectors::with_capacity(n as usize, self.labelvecs.nnz());
+        for (i, id) in ids.iter().enumerate() {
+            if id.is_some() {
+                nfeatures_list.push(self.nfeatures_list[i]);
+                labelvecs.push(self.labels(i as u32));
+            }
+        }
+        self.nfeatures_list = Buffer::from(nfeatures_list);
+        self.labelvecs = Cow::Owned(labelvecs);
+        self.relabeled.clear();
+        self.deleted = vec![false; n as usize];
+        self.ndeleted = 0;
+        ids
+    }
+
+    // nfeatures_list returns the number of the features of each entry.
+    pub(crate) fn nfeatures_list(&self) -> &[u32] {
+        &self.nfeatures_list
+    }
+
+    // indices returns the posting lists of the feature keys.
+    pub(crate) fn indices(&self) -> &PostingIndex {
+        &self.indices
+    }
+
+    /// freeze moves the plain posting lists into the CSR layout for the faster queries.
+    /// This does nothing if some posting lists are encoded.
+    pub fn freeze(&mut self) {
+        self.indices.freeze();
+    }
+
+    /// compress compresses the entry ids in the posting lists as PostingIds::Varint.
+    pub fn compress(&mut self) {
+        self.compress = true;
+        for index in self.indices.to_mut().values_mut() {
+            let encoding = index.weight_encoding();
+            index.reencode(true, encoding);
+        }
+    }
+
+    /// quantize encodes the f32 weights in the posting lists with the given encoding.
+    /// The weights already quantized are kept as they are.
+    pub fn quantize(&mut self, encoding: WeightEncoding) {
+        if encoding == WeightEncoding::F32 {
+            return;
+        }
+        self.weights = encoding;
+        for index in self.indices.to_mut().values_mut() {
+            let compress = index.is_compressed();
+            index.reencode(compress, encoding);
+        }
+    }
+
+    /// prune drops the low-weight postings by the method at the level, keeping the representation of the posting lists.
+    /// The posting lists are pruned one by one in place, where the Csr rows becoming empty are kept.
+    /// The numbers of the features of the entries are not changed, so the Jaccard similarities are of the unpruned entries.
+    pub fn prune(&mut self, method: PruneMethod, level: f32) -> PruneSummary {
+        let (npostings_before, memory_size_before) = (self.indices.npostings(), self.memory_size());
+        let mut buf = Vec::new();
+        self.indices.retain(|postings| {
+            let cutoff = PruneCutoff::new(postings, method, level, &mut buf);
+            move |i, v| { cutoff.keeps(i, v) }
+        });
+        PruneSummary{
+            method,
+            level,
+            npostings_before,
+            npostings_after: self.indices.npostings(),
+            memory_size_before,
+            memory_size_after: self.memory_size(),
+        }
+    }
+
+    // pruned_memory_size returns memory_size after prune by the method at the level, without pruning.
+    fn pruned_memory_size(&self, method: PruneMethod, level: f32) -> usize {
+        let mut buf = Vec::new();
+        self.indices.retained_memory_size(|postings| {
+            let cutoff = PruneCutoff::new(postings, method, level, &mut buf);
+            move |i, v| { cutoff.keeps(i, v) }
+        })
+    }
+
+    /// prune_to_budget is prune at the minimum level making memory_size at most budget, which is searched by bisection.
+    /// Each step of the bisection sizes the posting lists pruned at the level without copying them, and only the final level is pruned.
+    /// This returns None without pruning, if the budget is too small even for dropping all the postings.
+    pub fn prune_to_budget(&mut self, method: PruneMethod, budget: usize) -> Option<PruneSummary> {
+        let (npostings, memory_size) = (self.indices.npostings(), self.memory_size());
+        if memory_size <= budget {
+            return Some(PruneSummary{
+                method,
+                level: 0.0,
+                npostings_before: npostings,
+                npostings_after: npostings,
+                memory_size_before: memory_size,
+                memory_size_after: memory_size,
+            });
+        }
+        let max_level = match method {
+            PruneMethod::Threshold => {
+                let mut max = 0.0f32;
+                for key in self.indices.keys() {
+                    self.indices.for_each(key, |_, v| { max = max.max(v.abs()) });
+                }
+                max.next_up()
+            },
+            PruneMethod::Fraction => 1.0,
+        };
+        if self.pruned_memory_size(method, max_level) > budget {
+            return None;
+        }
+        // The level lo never fits into the budget, and the level hi always fits.
+        let (mut lo, mut hi) = (0.0f32, max_level);
+        for _ in 0..PRUNE_BISECTION_STEPS {
+            let level = lo + (hi - lo)/2.0;
+            if level <= lo || level >= hi {
+                break;
+            }
+            if self.pruned_memory_size(method, level) <= budget {
+                hi = level;
+            } else {
+                lo = level;
+            }
+        }
+        Some(self.prune(method, hi))
+    }
+
+    /// memory_size returns the number of the bytes used by the posting lists.
+    pub fn memory_size(&self) -> usize {
+        self.indices.memory_size()
+    }
+
+    /// find_nearests returns the top-S (entry, similarity) pairs of the training entries nearest to xi.
+    /// The similarity is the dot-product with the unnormalized xi weighted by the Jaccard similarity to the power beta.
+    /// The pairs are in the order of topk::ranks_before, so the entries with the same similarity are in the ascending order of the ids,
+    /// and the smaller ids are kept if the ties straddle the S-th place.
+    pub fn find_nearests(&self, xi: &[(u32, f32)], S: usize, beta: f32, ctx: &mut DatasetIndexContext) -> Vec<(u32, f32)> {
+        assert!(ctx.sim_counts.len() >= self.nfeatures_list.len(), "context is smaller than the index");
+        if ctx.strategy == ScanStrategy::MaxScore && beta == 0.0 {
+            if let PostingIndex::Csr(ref csr) = self.indices {
+                return self.find_nearests_maxscore(csr, xi, S, ctx);
+            }
+        }
+        let record = ctx.strategy != ScanStrategy::Full;
+        let (sim_counts, touched) = (&mut ctx.sim_counts, &mut ctx.touched);
+        for &(key, value) in xi {
+            self.indices.for_each(key, |i, v| {
+                let p = unsafe { sim_counts.get_unchecked_mut(i as usize) };
+                if record && p.1 == 0 {
+                    touched.push(i);
+                }
+                p.0 += value * v;
+                p.1 += 1;
+            });
+        }
+        // The context may be larger than the index, when it is shared by the shards.
+        let n = self.nfeatures_list.len();
+        let full_scan = match ctx.strategy {
+            ScanStrategy::Auto | ScanStrategy::MaxScore => touched.len() > n/AUTO_FULL_SCAN_RATIO,
+            ScanStrategy::Sparse => false,
+            ScanStrategy::Full => true,
+        };
+        let candidates = &mut ctx.candidates;
+        if full_scan {
+            for (i, p) in sim_counts[..n].iter_mut().enumerate() {
+                if p.1 > 0 {
+                    self.push_candidate(i as u32, *p, xi.len(), beta, candidates);
+                    *p = (0.0f32, 0);
+                }
+            }
+        } else {
+            for &i in touched.iter() {
+                let p = &mut sim_counts[i as usize];
+                self.push_candidate(i, *p, xi.len(), beta, candidates);
+                *p = (0.0f32, 0);
+            }
+        }
+        touched.clear();
+        let index_sims = top_k(candidates, S);
+        candidates.clear();
+        index_sims
+    }
+
+    // find_nearests_maxscore is find_nearests with beta=0 skipping the entries which cannot enter the top-S (MaxScore).
+    // The posting lists are sorted by the maximum contributions to the similarity.
+    // The lists in the prefix whose total maximum contribution cannot exceed the S-th similarity found so far are non-essential,
+    // and the entries only in them are never evaluated.
+    // The essential lists are accumulated in the windows of the entry ids, and each accumulated entry is rescored with all the lists,
+    // unless its partial similarity and the total maximum contribution of the non-essential lists cannot exceed the S-th similarity.
+    // The rescored similarity is summed in the order of xi, so it is exactly the same as the one of find_nearests.
+    fn find_nearests_maxscore(&self, csr: &CsrPostings, xi: &[(u32, f32)], S: usize, ctx: &mut DatasetIndexContext) -> Vec<(u32, f32)> {
+        if S == 0 {
+            return Vec::new();
+        }
+        // Each term is (value, postings, maximum contribution) in the order of xi.
+        let mut terms: Vec<_> = Vec::with_capacity(xi.len());
+        for &(key, value) in xi {
+            if let Some((postings, max_weight)) = csr.get_with_max_weight(key) {
+                if !postings.is_empty() {
+                    terms.push((value, postings, value.abs()*max_weight));
+                }
+            }
+        }
+        let npostings: usize = terms.iter().map(|term| { term.1.len() }).sum();
+        let mut order: Vec<usize> = (0..terms.len()).collect();
+        order.sort_by(|&a, &b| { terms[a].2.total_cmp(&terms[b].2) });
+        // bounds[k] is the total maximum contribution of the terms of order[..=k].
+        let mut bounds = Vec::with_capacity(terms.len());
+        let mut total = 0.0f32;
+        for &t in &order {
+            total += terms[t].2;
+            bounds.push(total);
+        }
+        // The slack covers the rounding errors of the sums in the different orders.
+        let slack = 2.0*(terms.len() as f32 + 1.0)*f32::EPSILON*total;
+        // The essential lists are accumulated in the order of xi, so the partial similarity is exact if no non-essential lists have the entry.
+        let mut essentials: Vec<usize> = (0..terms.len()).collect();
+        // positions are the positions accumulated in the essential lists, and searched in the non-essential lists.
+        // A list becoming non-essential is searched from its position, because the entries before it are below the window.
+        // Every posting before the final position has been read or passed over by the search.
+        let mut positions = vec![0usize; terms.len()];
+        let (sim_counts, candidates) = (&mut ctx.sim_counts, &mut ctx.candidates);
+        let (mut threshold, mut nessentials) = (0.0f32, 0);
+        let mut start = 0;
+        loop {
+            let prev_nessentials = nessentials;
+            while nessentials < terms.len() && bounds[nessentials] + slack <= threshold {
+                nessentials += 1;
+            }
+            if nessentials != prev_nessentials {
+                essentials = order[nessentials..].to_vec();
+                essentials.sort_unstable();
+            }
+            // The window starts from the smallest entry id remaining in the essential lists.
+            let next = essentials.iter().filter_map(|&t| { terms[t].1.get(positions[t]) }).map(|&(i, _)| { i as usize }).min();
+            start = match next {
+                Some(i) => start.max(i),
+                None => break,
+            };
+            let end = start + MAXSCORE_WINDOW_SIZE;
+            for &t in &essentials {
+                let (value, postings, _) = terms[t];
+                let mut k = positions[t];
+                while k < postings.len() && (postings[k].0 as usize) < end {
+                    let (i, v) = postings[k];
+                    let p = unsafe { sim_counts.get_unchecked_mut(i as usize) };
+                    p.0 += value * v;
+                    p.1 += 1;
+                    k += 1;
+                }
+                positions[t] = k;
+            }
+            let end = end.min(self.nfeatures_list.len());
+            for (i, p) in sim_counts[start..end].iter_mut().enumerate() {
+                if p.1 == 0 {
+                    continue;
+                }
+                let (i, (psim, pcount)) = (start + i, *p);
+                *p = (0.0f32, 0);
+                // The non-essential lists are searched in the descending order of the maximum contributions.
+                let (mut partial, mut nfound, mut pruned) = (psim, 0u32, false);
+                for k in (0..nessentials).rev() {
+                    if partial + bounds[k] + slack <= threshold {
+                        pruned = true;
+                        break;
+                    }
+                    let (value, postings, _) = terms[order[k]];
+                    let position = &mut positions[order[k]];
+                    *position = seek(postings, *position, i as u32);
+                    if let Some(&(j, v)) = postings.get(*position) {
+                        if j as usize == i {
+                            partial += value * v;
+                            nfound += 1;
+                            *position += 1;
+                        }
+                    }
+                }
+                if pruned {
+                    continue;
+                }
+                let (psim, pcount) = if nfound == 0 {
+                    (psim, pcount)
+                } else {
+                    // The similarity is summed again in the order of xi.
+                    let mut psim = 0.0f32;
+                    for &(value, postings, _) in &terms {
+                        if let Ok(k) = postings.binary_search_by_key(&(i as u32), |&(j, _)| { j }) {
+                            psim += value * postings[k].1;
+                        }
+                    }
+                    (psim, pcount + nfound)
+                };
+                self.push_candidate(i as u32, (psim, pcount), xi.len(), 0.0, candidates);
+                // The S-th similarity is updated when the candidates are filled at first, and whenever they are doubled.
+                if candidates.len() == 2*S || (candidates.len() == S && threshold == 0.0) {
+                    sort_largests_with_heap(candidates, S, ranks_before);
+                    candidates.truncate(S);
+                    threshold = candidates[S-1].1;
+                }
+            }
+            start = end;
+        }
+        let nread: usize = positions.iter().sum();
+        self.pruning.nqueries.fetch_add(1, Ordering::Relaxed);
+        self.pruning.npostings.fetch_add(npostings, Ordering::Relaxed);
+        self.pruning.nskipped.fetch_add(npostings - nread, Ordering::Relaxed);
+        let index_sims = top_k(candidates, S);
+        candidates.clear();
+        index_sims
+    }
+
+    /// pruning_stats returns the total numbers of the postings in the queries with ScanStrategy::MaxScore.
+    pub fn pruning_stats(&self) -> PruningStats {
+        PruningStats{
+            nqueries: self.pruning.nqueries.load(Ordering::Relaxed),
+            npostings: self.pruning.npostings.load(Ordering::Relaxed),
+            nskipped: self.pruning.nskipped.load(Ordering::Relaxed),
+        }
+    }
+
+    // push_candidate pushes the entry i with its similarity into candidates, if the similarity is positive.
+    fn push_candidate(&self, i: u32, (psim, pcount): (f32, u32), xilen: usize, beta: f32, candidates: &mut Vec<(u32, f32)>) {
+        if psim <= 0.0 || self.deleted[i as usize] {
+            return;
+        }
+        candidates.push((i, similarity(psim, pcount, self.nfeatures_list[i as usize], xilen, beta)));
+    }
+
+    /// new_context returns the context for find_nearests using the scan strategy of the index.
+    pub fn new_context(&self) -> DatasetIndexContext {
+        DatasetIndexContext{
+            sim_counts: vec![(0.0f32, 0); self.nfeatures_list.len()],
+            touched: Vec::new(),
+            candidates: Vec::new(),
+            strategy: self.strategy,
+        }
+    }
+
+    /// set_scan_strategy sets the scan strategy of the contexts made by new_context.
+    pub fn set_scan_strategy(&mut self, strategy: ScanStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// vote_labels returns the top-K (label, score) pairs voted by the neighbors found by find_nearests.
+    /// The pairs are in the order of topk::ranks_before, so the labels with the same score are in the ascending order.
+    /// Each neighbor votes its labels with the score (sim/xinorm)^alpha, where xinorm is the L2-norm of the query.
+    pub fn vote_labels(&self, index_sims: &[(u32, f32)], xinorm: f32, K: usize, alpha: f32) -> Vec<(u32, f32)> {
+        vote_labels(index_sims, xinorm, K, alpha, |j| { self.labels(j) })
+    }
+
+    pub fn size(&self) -> usize {
+        self.nfeatures_list.len()
+    }
+}
+
+/// vote_labels is DatasetIndex::vote_labels with the labels of each neighbor given by labels.
+pub fn vote_labels<'b, F: Fn(u32) -> &'b [u32]>(index_sims: &[(u32, f32)], xinorm: f32, K: usize, alpha: f32, labels: F) -> Vec<(u32, f32)> {
+    let mut label_hist: HashMap<u32, f32, BuildHasher> = HashMap::default();
+    for &(j, sim) in index_sims {
+        let sim = (sim/xinorm).powf(alpha);
+        for &label in labels(j) {
+            *label_hist.entry(label).or_insert(0.0f32) += sim
+        }
+    }
+    let mut label_freqs: Vec<(u32, f32)> = label_hist.into_iter().collect();
+    top_k(&mut label_freqs, K)
+}
+
+/// NeighborIndex is the index finding the nearest training entries of a query and voting their labels.
+/// The prediction and evaluation functions such as run_test accept any NeighborIndex.
+pub trait NeighborIndex: Sync {
+    /// Context is the working memory of a query, which can be reused but not shared between the threads.
+    type Context: Send;
+
+    /// new_context returns the context for find_nearests.
+    fn new_context(&self) -> Self::Context;
+
+    /// find_nearests returns the top-S (entry, similarity) pairs of the training entries nearest to xi in the order of topk::ranks_before.
+    fn find_nearests(&self, xi: &[(u32, f32)], S: usize, beta: f32, ctx: &mut Self::Context) -> Vec<(u32, f32)>;
+
+    /// labels returns the labels of the training entry.
+    fn labels(&self, i: u32) -> &[u32];
+
+    /// vote_labels returns the top-K (label, score) pairs voted by the neighbors found by find_nearests with their labels.
+    fn vote_labels(&self, index_sims: &[(u32, f32)], xinorm: f32, K: usize, alpha: f32) -> Vec<(u32, f32)> {
+        vote_labels(index_sims, xinorm, K, alpha, |j| { self.labels(j) })
+    }
+}
+
+impl<'a> NeighborIndex for DatasetIndex<'a> {
+    type Context = DatasetIndexContext;
+
+    fn new_context(&self) -> DatasetIndexContext {
+        DatasetIndex::new_context(self)
+    }
+
+    fn find_nearests(&self, xi: &[(u32, f32)], S: usize, beta: f32, ctx: &mut DatasetIndexContext) -> Vec<(u32, f32)> {
+        DatasetIndex::find_nearests(self, xi, S, beta, ctx)
+    }
+
+    fn labels(&self, i: u32) -> &[u32] {
+        DatasetIndex::labels(self, i)
+    }
+}
+
+/// RecallMeter is the NeighborIndex finding the neighbors with the approximate index, and measuring their recall against the exact index.
+/// The recall of a query is the fraction of the exact neighbors found by the approximate index.
+pub struct RecallMeter<'b, A: 'b, E: 'b> {
+    approx: &'b A,
+    exact: &'b E,
+    nqueries: AtomicUsize,
+    nexacts: AtomicUsize,
+    nfound: AtomicUsize,
+}
+
+impl<'b, A: NeighborIndex, E: NeighborIndex> RecallMeter<'b, A, E> {
+    pub fn new(approx: &'b A, exact: &'b E) -> RecallMeter<'b, A, E> {
+        RecallMeter{
+            approx,
+            exact,
+            nqueries: AtomicUsize::new(0),
+            nexacts: AtomicUsize::new(0),
+            nfound: AtomicUsize::new(0),
+        }
+    }
+
+    /// nqueries returns the number of the measured queries.
+    pub fn nqueries(&self) -> usize {
+        self.nqueries.load(Ordering::Relaxed)
+    }
+
+    /// recall returns the total fraction of the exact neighbors found by the approximate index, which is 1 if no exact neighbors exist.
+    pub fn recall(&self) -> f32 {
+        let nexacts = self.nexacts.load(Ordering::Relaxed);
+        if nexacts == 0 {
+            return 1.0;
+        }
+        (self.nfound.load(Ordering::Relaxed) as f32)/(nexacts as f32)
+    }
+}
+
+impl<'b, A: NeighborIndex, E: NeighborIndex> NeighborIndex for RecallMeter<'b, A, E> {
+    type Context = (A::Context, E::Context);
+
+    fn new_context(&self) -> (A::Context, E::Context) {
+        (self.approx.new_context(), self.exact.new_context())
+    }
+
+    fn find_nearests(&self, xi: &[(u32, f32)], S: usize, beta: f32, ctx: &mut (A::Context, E::Context)) -> Vec<(u32, f32)> {
+        let index_sims = self.approx.find_nearests(xi, S, beta, &mut ctx.0);
+        let exact_sims = self.exact.find_nearests(xi, S, beta, &mut ctx.1);
+        let nfound = exact_sims.iter().filter(|&&(i, _)| { index_sims.iter().any(|&(j, _)| { i == j }) }).count();
+        self.nqueries.fetch_add(1, Ordering::Relaxed);
+        self.nexacts.fetch_add(exact_sims.len(), Ordering::Relaxed);
+        self.nfound.fetch_add(nfound, Ordering::Relaxed);
+        index_sims
+    }
+
+    fn labels(&self, i: u32) -> &[u32] {
+        self.approx.labels(i)
+    }
+}
+
+// predict_entry returns the top-K predicted labels of xi, and the inspection text of the prediction if i is the multiple of positive per.
+#[allow(clippy::too_many_arguments)]
+fn predict_entry<N: NeighborIndex>(index: &N, ctx: &mut N::Context, i: usize, xi: &[(u32, f32)], yi: &[u32], K: usize, S: usize, alpha: f32, beta: f32, per: usize) -> (LabelVector, Option<String>) {
+    let xinorm = l2norm(xi);
+    let index_sims = index.find_nearests(xi, S, beta, ctx);
+    let labels_topK = index.vote_labels(&index_sims, xinorm, K, alpha);
+    let yihat: LabelVector = labels_topK.iter().map(|&(label, _)| { label }).collect();
+    let text = if per > 0 && i.is_multiple_of(per) {
+        Some(format!("i={}: \n    {:?}\n    -> {:?}\n       <> {:?}\n", i, index_sims, labels_topK, yi))
+    } else {
+        None
+    };
+    (yihat, text)
+}
+
+/// run_test returns the top-K predicted labels of each entry in ds.
+/// If per is positive, the prediction of every per-th entry is printed for inspection.
+pub fn run_test<N: NeighborIndex>(index: &N, ds: &Dataset, K: usize, S: usize, alpha: f32, beta: f32, per: usize) -> LabelVectors {
+    let mut yhat = LabelVectors::with_capacity(ds.size(), ds.size()*K);
+    let mut ctx = index.new_context();
+    for (i, (xi, yi)) in ds.into_iter().enumerate() {
+        let (yihat, text) = predict_entry(index, &mut ctx, i, xi, yi, K, S, alpha, beta, per);
+        if let Some(text) = text {
+            print!("{}", text);
+        }
+        yhat.push(&yihat);
+    }
+    yhat
+}
+
+/// run_test_stream predicts the top-K labels of each entry in entries, and accumulates the precisions into acc.
+/// The entries are consumed one by one, so the memory usage does not depend on the number of the entries.
+/// This returns the number of the tested entries, or the first error in entries.
+#[allow(clippy::too_many_arguments)]
+pub fn run_test_stream<N: NeighborIndex, I, X, Y, E>(index: &N, entries: I, K: usize, S: usize, alpha: f32, beta: f32, per: usize, acc: &mut PrecisionAccumulator) -> Result<usize, E>
+    where I: IntoIterator<Item = Result<(X, Y), E>>, X: AsRef<[(u32, f32)]>, Y: AsRef<[u32]> {
+    let mut ctx = index.new_context();
+    let mut n = 0;
+    for (i, entry) in entries.into_iter().enumerate() {
+        let (xi, yi) = entry?;
+        let (yihat, text) = predict_entry(index, &mut ctx, i, xi.as_ref(), yi.as_ref(), K, S, alpha, beta, per);
+        if let Some(text) = text {
+            print!("{}", text);
+        }
+        acc.add(&yihat, yi.as_ref());
+        n += 1;
+    }
+    Ok(n)
+}
+
+// The number of the entries predicted by each thread in a batch.
+const BATCH_SIZE_PER_THREAD: usize = 256;
+
+// predict_batch returns the predictions of the entries in the batch starting from the start-th entry, using a thread for each context.
+#[allow(clippy::too_many_arguments)]
+fn predict_batch<N: NeighborIndex, X, Y>(index: &N, batch: &[(X, Y)], start: usize, K: usize, S: usize, alpha: f32, beta: f32, per: usize, ctxs: &mut [N::Context]) -> Vec<(LabelVector, Option<String>)>
+    where X: AsRef<[(u32, f32)]> + Sync, Y: AsRef<[u32]> + Sync {
+    let chunk_size = batch.len().div_ceil(ctxs.len()).max(1);
+    thread::scope(|scope| {
+        let handles: Vec<_> = batch.chunks(chunk_size).zip(ctxs.iter_mut()).enumerate().map(|(c, (chunk, ctx))| {
+            scope.spawn(move || {
+                chunk.iter().enumerate().map(|(k, (xi, yi))| {
+                    predict_entry(index, ctx, start + c*chunk_size + k, xi.as_ref(), yi.as_ref(), K, S, alpha, beta, per)
+                }).collect::<Vec<_>>()
+            })
+        }).collect();
+        handles.into_iter().flat_map(|handle| { handle.join().unwrap() }).collect()
+    })
+}
+
+/// run_test_stream_parallel is run_test_stream predicting the entries with nthreads threads.
+/// The entries are read in batches, and each thread predicts a contiguous part of the batch with its own context.
+/// The predictions are accumulated and printed in the input order, so the results are the same as run_test_stream.
+#[allow(clippy::too_many_arguments)]
+pub fn run_test_stream_parallel<N: NeighborIndex, I, X, Y, E>(index: &N, entries: I, K: usize, S: usize, alpha: f32, beta: f32, per: usize, acc: &mut PrecisionAccumulator, nthreads: usize) -> Result<usize, E>
+    where I: IntoIterator<Item = Result<(X, Y), E>>, X: AsRef<[(u32, f32)]> + Sync, Y: AsRef<[u32]> + Sync {
+    let nthreads = nthreads.max(1);
+    let mut ctxs: Vec<N::Context> = (0..nthreads).map(|_| { index.new_context() }).collect();
+    let mut entries = entries.into_iter();
+    let mut batch = Vec::with_capacity(nthreads*BATCH_SIZE_PER_THREAD);
+    let mut n = 0;
+    loop {
+        // The entries before the error are predicted as run_test_stream does.
+        let mut error = None;
+        batch.clear();
+        for entry in entries.by_ref().take(nthreads*BATCH_SIZE_PER_THREAD) {
+            match entry {
+                Ok(entry) => batch.push(entry),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                },
+            }
+        }
+        let predictions = predict_batch(index, &batch, n, K, S, alpha, beta, per, &mut ctxs);
+        for ((_, yi), (yihat, text)) in batch.iter().zip(predictions) {
+            if let Some(text) = text {
+                print!("{}", text);
+            }
+            acc.add(&yihat, yi.as_ref());
+        }
+        n += batch.len();
+        if let Some(e) = error {
+            return Err(e);
+        }
+        if batch.len() < nthreads*BATCH_SIZE_PER_THREAD {
+            return Ok(n);
+        }
+    }
+}
+
+/// run_test_parallel is run_test predicting the entries with nthreads threads.
+/// The predictions are the same as run_test.
+#[allow(clippy::too_many_arguments)]
+pub fn run_test_parallel<N: NeighborIndex>(index: &N, ds: &Dataset, K: usize, S: usize, alpha: f32, beta: f32, per: usize, nthreads: usize) -> LabelVectors {
+    let nthreads = nthreads.max(1);
+    let mut ctxs: Vec<N::Context> = (0..nthreads).map(|_| { index.new_context() }).collect();
+    let entries: Vec<_> = ds.into_iter().collect();
+    let mut yhat = LabelVectors::with_capacity(ds.size(), ds.size()*K);
+    for (b, batch) in entries.chunks(nthreads*BATCH_SIZE_PER_THREAD).enumerate() {
+        for (yihat, text) in predict_batch(index, batch, b*nthreads*BATCH_SIZE_PER_THREAD, K, S, alpha, beta, per, &mut ctxs) {
+            if let Some(text) = text {
+                print!("{}", text);
+            }
+            yhat.push(&yihat);
+        }
+    }
+    yhat
+}
+
+/// PrecisionAccumulator accumulates Precision@K of the predictions entry by entry for each K.
+#[derive(Clone,Debug)]
+pub struct PrecisionAccumulator {
+    Ks: Vec<usize>,
+    sumPKs: Vec<f32>,
+    sumMaxPKs: Vec<f32>,
+    n: usize,
+}
+
+impl PrecisionAccumulator {
+    pub fn new(Ks: &[usize]) -> PrecisionAccumulator {
+        PrecisionAccumulator{
+            Ks: Ks.to_vec(),
+            sumPKs: vec![0.0f32; Ks.len()],
+            sumMaxPKs: vec![0.0f32; Ks.len()],
+            n: 0,
+        }
+    }
+
+    /// add accumulates the prediction yihat against the true labels yi.
+    pub fn add(&mut self, yihat: &[u32], yi: &[u32]) {
+        let mut yimap: HashSet<u32, BuildHasher> = HashSet::default();
+        for label in yi {
+            yimap.insert(*label);
+        }
+        for (k, &K) in self.Ks.iter().enumerate() {
+            let mut pKi = 0;
+            for label in yihat.iter().take(K) {
+                if yimap.contains(label) {
+                    pKi += 1;
+                }
+            }
+            self.sumPKs[k] += (pKi as f32)/(K as f32);
+            self.sumMaxPKs[k] += (yi.len().min(K) as f32)/(K as f32);
+        }
+        self.n += 1;
+    }
+
+    /// len returns the number of the accumulated entries.
+    pub fn len(&self) -> usize {
+        self.n
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    /// precision returns the averaged Precision@K, and its maximum achievable value.
+    /// This returns None if K was not given at the construction.
+    pub fn precision(&self, K: usize) -> Option<(f32, f32)> {
+        let k = self.Ks.iter().position(|&k| { k == K })?;
+        Some((self.sumPKs[k]/(self.n as f32), self.sumMaxPKs[k]/(self.n as f32)))
+    }
+}
+
+/// report_precision returns the averaged Precision@K of Yhat against Y, and its maximum achievable value.
+pub fn report_precision(Yhat: &LabelVectors, Y: &LabelVectors, K: usize) -> (f32, f32) {
+    let mut acc = PrecisionAccumulator::new(&[K]);
+    for (yihat, yi) in Yhat.iter().zip(Y.iter()) {
+        acc.add(yihat, yi);
+    }
+    acc.precision(K).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::random_dataset;
+
+    #[test]
+    fn maxscore_matches_exhaustive() {
+        // Each entry is duplicated, so that every similarity ties at least once.
+        let mut ds = Dataset::new();
+        for (xi, yi) in &random_dataset(300, 40, 10, 4) {
+            ds.push(xi, yi);
+            ds.push(xi, yi);
+        }
+        let mut queries = random_dataset(50, 40, 10, 5);
+        // The features of zero value, or missing in the index, are non-essential from the start.
+        queries.push(&[(3, 0.0), (7, 0.0)], &[]);
+        queries.push(&[(100, 1.0), (200, 0.5)], &[]);
+        queries.push(&[(3, 0.0), (5, 1.0), (100, 1.0)], &[]);
+        let exact = DatasetIndex::new(&ds);
+        let mut maxscore = DatasetIndex::new(&ds);
+        maxscore.set_scan_strategy(ScanStrategy::MaxScore);
+        let (mut exact_ctx, mut ctx) = (exact.new_context(), maxscore.new_context());
+        for (xi, _) in &queries {
+            // The largest S exceeds the number of the candidates.
+            for &S in &[1, 2, 3, 10, 1000] {
+                let expected = exact.find_nearests(xi, S, 0.0, &mut exact_ctx);
+                assert_eq!(maxscore.find_nearests(xi, S, 0.0, &mut ctx), expected, "xi={:?} S={}", xi, S);
+            }
+        }
+        let stats = maxscore.pruning_stats();
+        assert_eq!(stats.nqueries, 5*queries.size());
+        assert!(stats.nskipped <= stats.npostings);
+    }
+
+    #[test]
+    fn maxscore_skips_postings_of_non_essential_queries() {
+        let ds = random_dataset(300, 40, 10, 6);
+        let mut index = DatasetIndex::new(&ds);
+        index.set_scan_strategy(ScanStrategy::MaxScore);
+        let mut ctx = index.new_context();
+        assert!(index.find_nearests(&[(3, 0.0), (7, 0.0)], 10, 0.0, &mut ctx).is_empty());
+        let stats = index.pruning_stats();
+        assert!(stats.npostings > 0);
+        assert_eq!(stats.nskipped, stats.npostings);
+    }
+
+    // Entry is the features and the labels of an entry, which is None if deleted.
+    type Entry = Option<(Vec<(u32, f32)>, Vec<u32>)>;
+
+    // assert_same_nearests asserts that the index finds the same neighbors and labels as the index freshly built from the live entries,
+    // whose ids are renumbered in the same order.
+    fn assert_same_nearests(index: &DatasetIndex, live: &[Entry], queries: &Dataset) {
+        let mut ds = Dataset::new();
+        let mut ids = Vec::new();
+        for (i, entry) in live.iter().enumerate() {
+            if let Some((xi, yi)) = entry {
+                ds.push(xi, yi);
+                ids.push(i as u32);
+            }
+        }
+        let fresh = DatasetIndex::new(&ds);
+        let (mut ctx, mut fresh_ctx) = (index.new_context(), fresh.new_context());
+        for (xi, _) in queries {
+            for &S in &[1, 5, 100] {
+                let expected: Vec<_> = fresh.find_nearests(xi, S, 0.5, &mut fresh_ctx).into_iter().map(|(j, sim)| { (ids[j as usize], sim) }).collect();
+                let actual = index.find_nearests(xi, S, 0.5, &mut ctx);
+                assert_eq!(actual, expected);
+                assert_eq!(index.vote_labels(&actual, 1.0, 5, 1.0), fresh.vote_labels(&fresh.find_nearests(xi, S, 0.5, &mut fresh_ctx), 1.0, 5, 1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_updates_match_rebuild() {
+        let ds = random_dataset(60, 30, 10, 13);
+        let inserted = random_dataset(20, 30, 10, 14);
+        let queries = random_dataset(30, 30, 10, 15);
+        let mut index = DatasetIndex::new(&ds);
+        let mut live: Vec<Entry> = ds.into_iter().map(|(xi, yi)| { Some((xi.to_vec(), yi.to_vec())) }).collect();
+        for (k, (xi, yi)) in inserted.into_iter().enumerate() {
+            assert_eq!(index.insert(xi, yi) as usize, live.len());
+            live.push(Some((xi.to_vec(), yi.to_vec())));
+            // The last entry is deleted right after its insertion, and some entries are deleted twice.
+            let i = match k%4 {
+                0 => live.len() - 1,
+                1 => 7*k%live.len(),
+                _ => (3*k + 1)%live.len(),
+            };
+            assert_eq!(index.delete(i as u32), live[i].is_some());
+            assert!(!index.delete(i as u32));
+            live[i] = None;
+            let j = (5*k + 2)%live.len();
+            let yj = [k as u32%10];
+            assert_eq!(index.update_labels(j as u32, &yj), live[j].is_some());
+            if let Some((_, ref mut yi)) = live[j] {
+                *yi = yj.to_vec();
+            }
+        }
+        assert!(!index.delete(live.len() as u32));
+        assert_eq!(index.ndeleted(), live.iter().filter(|entry| { entry.is_none() }).count());
+        assert_same_nearests(&index, &live, &queries);
+        let ids = index.compact();
+        let mut n = 0;
+        for (id, entry) in ids.iter().zip(&live) {
+            assert_eq!(*id, entry.as_ref().map(|_| { n }));
+            n += entry.is_some() as u32;
+        }
+        live.retain(|entry| { entry.is_some() });
+        assert_eq!(index.size(), live.len());
+        assert_same_nearests(&index, &live, &queries);
+        // The last entry is deleted after the compaction, and the compaction without tombstones keeps the index.
+        let last = live.len() - 1;
+        assert!(index.delete(last as u32));
+        live[last] = None;
+        index.compact();
+        live.pop();
+        index.compact();
+        assert_same_nearests(&index, &live, &queries);
+    }
+
+    #[test]
+    fn update_labels_are_merged_by_compact() {
+        let ds = random_dataset(50, 20, 10, 16);
+        let mut index = DatasetIndex::new(&ds);
+        let mut expected: Vec<Vec<u32>> = ds.Y.iter().map(|yi| { yi.to_vec() }).collect();
+        for k in 0..200 {
+            let i = (7*k + 3)%ds.size();
+            let yi: Vec<u32> = (0..k%4).map(|j| { ((k + j)%10) as u32 }).collect();
+            assert!(index.update_labels(i as u32, &yi));
+            expected[i] = yi;
+        }
+        // The label vectors are not changed until the compaction.
+        assert_eq!(*index.labelvecs, ds.Y);
+        for (i, yi) in expected.iter().enumerate() {
+            assert_eq!(index.labels(i as u32), &yi[..]);
+        }
+        assert!(index.delete(3));
+        index.compact();
+        assert!(index.relabeled.is_empty());
+        expected.remove(3);
+        let labelvecs: Vec<Vec<u32>> = index.labelvecs.iter().map(|yi| { yi.to_vec() }).collect();
+        assert_eq!(labelvecs, expected);
+    }
+
+    #[test]
+    fn insert_keeps_the_representation() {
+        let ds = random_dataset(40, 20, 10, 17);
+        // The inserted entries have the new keys beyond the keys of ds.
+        let inserted = random_dataset(20, 40, 10, 18);
+        for &(compress, weights) in &[(false, WeightEncoding::F32), (true, WeightEncoding::F32), (false, WeightEncoding::F16), (true, WeightEncoding::U8)] {
+            let opts = IndexOptions{ compress, weights, ..IndexOptions::default() };
+            let mut index = DatasetIndex::with_options(&ds, &opts);
+            for (xi, yi) in &inserted {
+                index.insert(xi, yi);
+            }
+            match index.indices {
+                PostingIndex::Map(ref map) => {
+                    assert!(map.len() > 20);
+                    for list in map.values() {
+                        assert_eq!((list.is_compressed(), list.weight_encoding()), (compress, weights));
+                    }
+                },
+                PostingIndex::Csr(_) => panic!("inserted into Csr"),
+            }
+            if weights == WeightEncoding::F32 {
+                let live: Vec<Entry> = ds.into_iter().chain(&inserted).map(|(xi, yi)| { Some((xi.to_vec(), yi.to_vec())) }).collect();
+                assert_same_nearests(&index, &live, &inserted);
+            }
+        }
+    }
+
+    // postings_of returns the posting lists of the index in the order of the keys.
+    fn postings_of(index: &DatasetIndex) -> Vec<(u32, Vec<(u32, f32)>)> {
+        index.indices().keys().into_iter().map(|key| {
+            let mut postings = Vec::new();
+            index.indices().for_each(key, |i, v| { postings.push((i, v)) });
+            (key, postings)
+        }).collect()
+    }
+
+    #[test]
+    fn build_indices_is_independent_of_nthreads() {
+        let ds = random_dataset(50, 30, 10, 16);
+        for &compress in &[false, true] {
+            let opts = IndexOptions{ nthreads: 1, compress, ..IndexOptions::default() };
+            let expected = DatasetIndex::with_options(&ds, &opts);
+            let expected_range = DatasetIndex::with_range(&ds, 10..45, &opts);
+            for &nthreads in &[2, 7, 50, 51, 200] {
+                let index = DatasetIndex::with_options(&ds, &IndexOptions{ nthreads, ..opts.clone() });
+                assert_eq!(index.nfeatures_list(), expected.nfeatures_list(), "nthreads={}", nthreads);
+                assert_eq!(postings_of(&index), postings_of(&expected), "nthreads={}", nthreads);
+                let ranged = DatasetIndex::with_range(&ds, 10..45, &IndexOptions{ nthreads, ..opts.clone() });
+                assert_eq!(ranged.nfeatures_list(), expected_range.nfeatures_list(), "nthreads={}", nthreads);
+                assert_eq!(postings_of(&ranged), postings_of(&expected_range), "nthreads={}", nthreads);
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_tests_match_sequential() {
+        let ds = random_dataset(200, 30, 10, 17);
+        let index = DatasetIndex::new(&ds);
+        // The last batch of 1000 entries is not a multiple of 3 or 7 entries, and the small tables have fewer entries than the threads.
+        for &n in &[1, 2, 5, 1000] {
+            let test_ds = random_dataset(n, 30, 10, 18);
+            let expected = run_test(&index, &test_ds, 5, 10, 1.0, 0.5, 0);
+            let mut expected_acc = PrecisionAccumulator::new(&[1, 3, 5]);
+            let entries = test_ds.into_iter().map(|entry| { Ok::<_, ()>(entry) });
+            assert_eq!(run_test_stream(&index, entries, 5, 10, 1.0, 0.5, 0, &mut expected_acc), Ok(n));
+            for &nthreads in &[1, 2, 3, 7, 8] {
+                assert_eq!(run_test_parallel(&index, &test_ds, 5, 10, 1.0, 0.5, 0, nthreads), expected, "n={} nthreads={}", n, nthreads);
+                let mut acc = PrecisionAccumulator::new(&[1, 3, 5]);
+                let entries = test_ds.into_iter().map(|entry| { Ok::<_, ()>(entry) });
+                assert_eq!(run_test_stream_parallel(&index, entries, 5, 10, 1.0, 0.5, 0, &mut acc, nthreads), Ok(n));
+                for &K in &[1, 3, 5] {
+                    assert_eq!(acc.precision(K), expected_acc.precision(K), "n={} nthreads={} K={}", n, nthreads, K);
+                    assert_eq!(acc.precision(K), Some(report_precision(&expected, &test_ds.Y, K)));
+                }
+            }
+        }
+    }
+
+    // pruned_postings returns the postings kept by the method at the level, sorting the postings in the dropping order.
+    fn pruned_postings(postings: &[(u32, f32)], method: PruneMethod, level: f32) -> Vec<(u32, f32)> {
+        match method {
+            PruneMethod::Threshold => postings.iter().filter(|&&(_, v)| { v.abs() >= level }).cloned().collect(),
+            PruneMethod::Fraction => {
+                let mut order = postings.to_vec();
+                order.sort_by(|a, b| { a.1.abs().total_cmp(&b.1.abs()).then(b.0.cmp(&a.0)) });
+                let ndropped = (level*(postings.len() as f32)).floor() as usize;
+                let mut kept = order[ndropped.min(order.len())..].to_vec();
+                kept.sort_by_key(|&(i, _)| { i });
+                kept
+            },
+        }
+    }
+
+    #[test]
+    fn prune_keeps_the_highest_weights_in_place() {
+        let ds = random_dataset(200, 30, 10, 19);
+        for &(compress, weights) in &[(false, WeightEncoding::F32), (true, WeightEncoding::F32), (false, WeightEncoding::U8)] {
+            let opts = IndexOptions{ compress, weights, ..IndexOptions::default() };
+            let original = postings_of(&DatasetIndex::with_options(&ds, &opts));
+            for &(method, level) in &[(PruneMethod::Fraction, 0.0), (PruneMethod::Fraction, 0.3), (PruneMethod::Fraction, 0.99), (PruneMethod::Fraction, 1.0), (PruneMethod::Threshold, 0.3), (PruneMethod::Threshold, 0.6)] {
+                let mut index = DatasetIndex::with_options(&ds, &opts);
+                let expected_size = index.pruned_memory_size(method, level);
+                let summary = index.prune(method, level);
+                let expected: Vec<_> = original.iter().map(|(key, postings)| { (*key, pruned_postings(postings, method, level)) }).filter(|(_, postings)| { !postings.is_empty() }).collect();
+                assert_eq!(postings_of(&index), expected, "{:?} {}", method, level);
+                assert_eq!(summary.npostings_after, expected.iter().map(|(_, postings)| { postings.len() }).sum::<usize>());
+                assert_eq!(summary.memory_size_after, expected_size, "{:?} {}", method, level);
+                assert_eq!(index.memory_size(), expected_size);
+            }
+        }
+    }
+
+    #[test]
+    fn prune_to_budget_finds_the_minimum_level() {
+        let ds = random_dataset(200, 30, 10, 20);
+        for &compress in &[false, true] {
+            let opts = IndexOptions{ compress, ..IndexOptions::default() };
+            for &method in &[PruneMethod::Fraction, PruneMethod::Threshold] {
+                let full = DatasetIndex::with_options(&ds, &opts).memory_size();
+                for &budget in &[full, 3*full/4, full/3] {
+                    let mut index = DatasetIndex::with_options(&ds, &opts);
+                    let summary = index.prune_to_budget(method, budget).unwrap();
+                    assert!(index.memory_size() <= budget);
+                    assert_eq!(index.memory_size(), summary.memory_size_after);
+                    if summary.level > 0.0 {
+                        let lower = summary.level*(1.0 - 1e-4);
+                        assert!(DatasetIndex::with_options(&ds, &opts).pruned_memory_size(method, lower) > budget, "{:?} {}", method, budget);
+                    }
+                }
+                // The empty Map posting lists are removed, but the lookup tables of the Csr ones are kept.
+                assert_eq!(DatasetIndex::with_options(&ds, &opts).prune_to_budget(method, 0).is_some(), compress);
+            }
+        }
+    }
+}
@@ -0,0 +1,896 @@
+use std::collections::HashMap;
+use std::mem;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use csr::{Buffer,CsrVectors};
+use hash::BuildHasher;
+
+/// PostingList is the list of the (entry id, weight) postings of a feature in the ascending order of the entry ids.
+pub enum PostingList {
+    /// Plain stores the postings as they are, which costs 8 bytes per posting.
+    Plain(Buffer<(u32, f32)>),
+    /// Encoded stores the entry ids and the weights separately in the given encodings.
+    Encoded{ ids: PostingIds, weights: PostingWeights },
+}
+
+/// PostingIds is the entry ids of the postings.
+pub enum PostingIds {
+    Plain(Vec<u32>),
+    /// Varint stores the deltas of the entry ids in the LEB128 variable-length bytes.
+    Varint{ bytes: Vec<u8>, len: usize, last: u32 },
+}
+
+/// WeightEncoding is the encoding of the weights of the postings.
+#[derive(Clone,Copy,Debug,Default,Eq,PartialEq)]
+pub enum WeightEncoding {
+    #[default]
+    F32,
+    /// F16 stores the weights in the IEEE 754 half-precision floats.
+    F16,
+    /// U8 quantizes the weights in each posting list into 256 levels between their minimum and maximum.
+    U8,
+}
+
+impl FromStr for WeightEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(WeightEncoding::F32),
+            "f16" => Ok(WeightEncoding::F16),
+            "u8" => Ok(WeightEncoding::U8),
+            _ => Err(format!("unknown weight encoding: {}", s)),
+        }
+    }
+}
+
+/// PostingWeights is the weights of the postings.
+pub enum PostingWeights {
+    F32(Vec<f32>),
+    F16(Vec<u16>),
+    /// U8 dequantizes each value q into min + q*scale.
+    /// The weights pushed out of the range of the quantized ones follow them in exact, which are kept as they are until the index is built again,
+    /// so that the quantized weights are never requantized.
+    U8{ min: f32, scale: f32, values: Vec<u8>, exact: Vec<f32> },
+}
+
+// push_varint appends x in the LEB128 bytes.
+fn push_varint(bytes: &mut Vec<u8>, mut x: u32) {
+    while x >= 0x80 {
+        bytes.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    bytes.push(x as u8);
+}
+
+// varint_len returns the number of the LEB128 bytes of x.
+fn varint_len(mut x: u32) -> usize {
+    let mut n = 1;
+    while x >= 0x80 {
+        x >>= 7;
+        n += 1;
+    }
+    n
+}
+
+/// f32_to_f16 returns the nearest half-precision float of x, rounding to even.
+pub fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mant = bits & 0x7fffff;
+    if exp == 0xff {
+        // Infinity or NaN.
+        return sign | 0x7c00 | if mant != 0 { 0x200 } else { 0 };
+    }
+    let e = exp - 127 + 15;
+    if e >= 31 {
+        return sign | 0x7c00;
+    }
+    if e <= 0 {
+        // Subnormal or zero.
+        if e < -10 {
+            return sign;
+        }
+        let m = mant | 0x800000;
+        let shift = (14 - e) as u32;
+        let rounded = (m + (1 << (shift - 1)) - 1 + ((m >> shift) & 1)) >> shift;
+        return sign | rounded as u16;
+    }
+    let (m, rest) = (mant >> 13, mant & 0x1fff);
+    let mut h = (e as u32) << 10 | m;
+    // The carry into the exponent is also correct.
+    if rest > 0x1000 || (rest == 0x1000 && m & 1 == 1) {
+        h += 1;
+    }
+    sign | h as u16
+}
+
+/// f16_to_f32 returns the single-precision float of the half-precision float h.
+#[inline]
+pub fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h & 0x8000) as u32) << 16;
+    let bits = ((h & 0x7fff) as u32) << 13;
+    if bits >= 0x0f800000 {
+        // Infinity or NaN.
+        return f32::from_bits(sign | 0x7f800000 | bits);
+    }
+    // Multiplying 2^112 rebiases the exponent, and normalizes the subnormals at the same time.
+    f32::from_bits(sign | (f32::from_bits(bits)*f32::from_bits(0x77800000)).to_bits())
+}
+
+// f16_table returns the table of f16_to_f32 for all the half-precision floats.
+fn f16_table() -> &'static [f32] {
+    static TABLE: OnceLock<Vec<f32>> = OnceLock::new();
+    TABLE.get_or_init(|| { (0..=0xffffu32).map(|h| { f16_to_f32(h as u16) }).collect() })
+}
+
+impl PostingIds {
+    pub fn new(ids: &[u32], compress: bool) -> PostingIds {
+        if compress {
+            let mut varint = PostingIds::Varint{ bytes: Vec::with_capacity(ids.len()), len: 0, last: 0 };
+            for &i in ids {
+                varint.push(i);
+            }
+            varint
+        } else {
+            PostingIds::Plain(ids.to_vec())
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            PostingIds::Plain(ref ids) => ids.len(),
+            PostingIds::Varint{ len, .. } => len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        matches!(*self, PostingIds::Varint{ .. })
+    }
+
+    pub fn memory_size(&self) -> usize {
+        match *self {
+            PostingIds::Plain(ref ids) => ids.len()*mem::size_of::<u32>(),
+            PostingIds::Varint{ ref bytes, .. } => bytes.len(),
+        }
+    }
+
+    /// push appends the entry id, which must not be smaller than the last one.
+    pub fn push(&mut self, i: u32) {
+        match *self {
+            PostingIds::Plain(ref mut ids) => ids.push(i),
+            PostingIds::Varint{ ref mut bytes, ref mut len, ref mut last } => {
+                debug_assert!(*len == 0 || i >= *last, "entry ids must be ascending");
+                push_varint(bytes, i - *last);
+                *len += 1;
+                *last = i;
+            },
+        }
+    }
+
+    /// for_each_with calls f with each entry id and the corresponding weight in weights.
+    #[inline]
+    pub fn for_each_with<W: Iterator<Item = f32>, F: FnMut(u32, f32)>(&self, weights: W, mut f: F) {
+        match *self {
+            PostingIds::Plain(ref ids) => {
+                for (&i, v) in ids.iter().zip(weights) {
+                    f(i, v);
+                }
+            },
+            PostingIds::Varint{ ref bytes, .. } => {
+                let (mut i, mut pos) = (0u32, 0);
+                for v in weights {
+                    let (mut delta, mut shift) = (0u32, 0);
+                    loop {
+                        let b = bytes[pos];
+                        pos += 1;
+                        delta |= ((b & 0x7f) as u32) << shift;
+                        if b < 0x80 {
+                            break;
+                        }
+                        shift += 7;
+                    }
+                    i += delta;
+                    f(i, v);
+                }
+            },
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u32> {
+        let mut ids = Vec::with_capacity(self.len());
+        self.for_each_with((0..self.len()).map(|_| { 0.0f32 }), |i, _| { ids.push(i) });
+        ids
+    }
+}
+
+impl PostingWeights {
+    pub fn new(weights: &[f32], encoding: WeightEncoding) -> PostingWeights {
+        match encoding {
+            WeightEncoding::F32 => PostingWeights::F32(weights.to_vec()),
+            WeightEncoding::F16 => PostingWeights::F16(weights.iter().map(|&v| { f32_to_f16(v) }).collect()),
+            WeightEncoding::U8 => {
+                let min = weights.iter().cloned().fold(f32::INFINITY, f32::min);
+                let max = weights.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                let scale = if max > min { (max - min)/255.0 } else { 0.0 };
+                let values = weights.iter().map(|&v| {
+                    if scale > 0.0 { ((v - min)/scale).round().clamp(0.0, 255.0) as u8 } else { 0 }
+                }).collect();
+                PostingWeights::U8{ min: if min.is_finite() { min } else { 0.0 }, scale, values, exact: Vec::new() }
+            },
+        }
+    }
+
+    pub fn encoding(&self) -> WeightEncoding {
+        match *self {
+            PostingWeights::F32(_) => WeightEncoding::F32,
+            PostingWeights::F16(_) => WeightEncoding::F16,
+            PostingWeights::U8{ .. } => WeightEncoding::U8,
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        match *self {
+            PostingWeights::F32(ref weights) => weights.len()*mem::size_of::<f32>(),
+            PostingWeights::F16(ref weights) => weights.len()*mem::size_of::<u16>(),
+            PostingWeights::U8{ ref values, ref exact, .. } => 2*mem::size_of::<f32>() + values.len() + exact.len()*mem::size_of::<f32>(),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<f32> {
+        match *self {
+            PostingWeights::F32(ref weights) => weights.clone(),
+            PostingWeights::F16(ref weights) => weights.iter().map(|&h| { f16_to_f32(h) }).collect(),
+            PostingWeights::U8{ min, scale, ref values, ref exact } => values.iter().map(|&q| { min + (q as f32)*scale }).chain(exact.iter().cloned()).collect(),
+        }
+    }
+
+    // push appends the weight, which is kept exact if the U8 weights cannot represent it within their quantization error.
+    fn push(&mut self, v: f32) {
+        match *self {
+            PostingWeights::F32(ref mut weights) => weights.push(v),
+            PostingWeights::F16(ref mut weights) => weights.push(f32_to_f16(v)),
+            PostingWeights::U8{ min, scale, ref mut values, ref exact } if exact.is_empty() && v >= min && v <= min + 255.0*scale => {
+                values.push(if scale > 0.0 { ((v - min)/scale).round().clamp(0.0, 255.0) as u8 } else { 0 });
+            },
+            PostingWeights::U8{ ref mut exact, .. } => exact.push(v),
+        }
+    }
+
+    // retain keeps only the weights at the positions where keep is true.
+    fn retain(&mut self, keep: &[bool]) {
+        fn retain_vec<T>(v: &mut Vec<T>, keep: &[bool]) {
+            let mut k = 0;
+            v.retain(|_| { k += 1; keep[k-1] });
+        }
+        match *self {
+            PostingWeights::F32(ref mut weights) => retain_vec(weights, keep),
+            PostingWeights::F16(ref mut weights) => retain_vec(weights, keep),
+            PostingWeights::U8{ ref mut values, ref mut exact, .. } => {
+                let nvalues = values.len();
+                retain_vec(values, &keep[..nvalues]);
+                retain_vec(exact, &keep[nvalues..]);
+            },
+        }
+    }
+}
+
+impl PostingList {
+    /// encode returns the postings encoded with the given options.
+    /// If compress is false and the encoding is F32, this returns the Plain postings.
+    pub fn encode(postings: &[(u32, f32)], compress: bool, encoding: WeightEncoding) -> PostingList {
+        if !compress && encoding == WeightEncoding::F32 {
+            return PostingList::from(postings.to_vec());
+        }
+        let ids: Vec<u32> = postings.iter().map(|&(i, _)| { i }).collect();
+        let weights: Vec<f32> = postings.iter().map(|&(_, v)| { v }).collect();
+        PostingList::Encoded{
+            ids: PostingIds::new(&ids, compress),
+            weights: PostingWeights::new(&weights, encoding),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            PostingList::Plain(ref postings) => postings.len(),
+            PostingList::Encoded{ ref ids, .. } => ids.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// is_compressed returns true if the entry ids are compressed.
+    pub fn is_compressed(&self) -> bool {
+        match *self {
+            PostingList::Plain(_) => false,
+            PostingList::Encoded{ ref ids, .. } => ids.is_compressed(),
+        }
+    }
+
+    /// weight_encoding returns the encoding of the weights.
+    pub fn weight_encoding(&self) -> WeightEncoding {
+        match *self {
+            PostingList::Plain(_) => WeightEncoding::F32,
+            PostingList::Encoded{ ref weights, .. } => weights.encoding(),
+        }
+    }
+
+    /// memory_size returns the number of the bytes used by the postings, excluding the unused capacity.
+    pub fn memory_size(&self) -> usize {
+        match *self {
+            PostingList::Plain(ref postings) => postings.len()*mem::size_of::<(u32, f32)>(),
+            PostingList::Encoded{ ref ids, ref weights } => ids.memory_size() + weights.memory_size(),
+        }
+    }
+
+    /// push appends the posting, whose entry id must not be smaller than the last one.
+    pub fn push(&mut self, i: u32, v: f32) {
+        match *self {
+            PostingList::Plain(ref mut postings) => postings.to_mut().push((i, v)),
+            PostingList::Encoded{ ref mut ids, ref mut weights } => {
+                ids.push(i);
+                weights.push(v);
+            },
+        }
+    }
+
+    /// for_each calls f with each posting in the ascending order of the entry ids.
+    /// The weights are dequantized on the fly.
+    #[inline]
+    pub fn for_each<F: FnMut(u32, f32)>(&self, f: F) {
+        match *self {
+            PostingList::Plain(ref postings) => {
+                let mut f = f;
+                for &(i, v) in postings.iter() {
+                    f(i, v);
+                }
+            },
+            PostingList::Encoded{ ref ids, ref weights } => match *weights {
+                PostingWeights::F32(ref weights) => ids.for_each_with(weights.iter().cloned(), f),
+                PostingWeights::F16(ref weights) => {
+                    let table = f16_table();
+                    ids.for_each_with(weights.iter().map(|&h| { table[h as usize] }), f)
+                },
+                PostingWeights::U8{ min, scale, ref values, ref exact } => {
+                    let values = values.iter().map(|&q| { min + (q as f32)*scale });
+                    if exact.is_empty() {
+                        ids.for_each_with(values, f)
+                    } else {
+                        ids.for_each_with(values.chain(exact.iter().cloned()), f)
+                    }
+                },
+            },
+        }
+    }
+
+    /// to_vec returns the decoded postings.
+    pub fn to_vec(&self) -> Vec<(u32, f32)> {
+        let mut postings = Vec::with_capacity(self.len());
+        self.for_each(|i, v| { postings.push((i, v)) });
+        postings
+    }
+
+    /// reencode encodes the entry ids and the weights again, where the weights are kept as they are unless they are F32.
+    pub fn reencode(&mut self, compress: bool, encoding: WeightEncoding) {
+        if self.is_compressed() == compress && self.weight_encoding() == encoding {
+            return;
+        }
+        if let PostingList::Encoded{ ref mut ids, ref mut weights } = *self {
+            if weights.encoding() != WeightEncoding::F32 {
+                if ids.is_compressed() != compress {
+                    *ids = PostingIds::new(&ids.to_vec(), compress);
+                }
+                return;
+            }
+        }
+        *self = PostingList::encode(&self.to_vec(), compress, encoding);
+    }
+
+    /// retain_map keeps only the postings whose entry ids are mapped to some ids by f, and replaces their ids.
+    /// f must keep the ascending order of the entry ids.
+    /// The weights are not encoded again, so that the quantization errors are not accumulated.
+    pub fn retain_map<F: Fn(u32) -> Option<u32>>(&mut self, f: F) {
+        match *self {
+            PostingList::Plain(ref mut postings) => {
+                let retained: Vec<(u32, f32)> = postings.iter().filter_map(|&(i, v)| { f(i).map(|i| { (i, v) }) }).collect();
+                *postings = Buffer::from(retained);
+            },
+            PostingList::Encoded{ ref mut ids, ref mut weights } => {
+                let old_ids = ids.to_vec();
+                let keep: Vec<bool> = old_ids.iter().map(|&i| { f(i).is_some() }).collect();
+                let new_ids: Vec<u32> = old_ids.iter().filter_map(|&i| { f(i) }).collect();
+                *ids = PostingIds::new(&new_ids, ids.is_compressed());
+                weights.retain(&keep);
+            },
+        }
+    }
+
+    /// retain keeps only the postings for which f returns true.
+    /// The weights are not encoded again, so that the quantization errors are not accumulated.
+    pub fn retain<F: FnMut(u32, f32) -> bool>(&mut self, mut f: F) {
+        if let PostingList::Plain(ref mut postings) = *self {
+            postings.to_mut().retain(|&(i, v)| { f(i, v) });
+            return;
+        }
+        let mut keep = Vec::with_capacity(self.len());
+        self.for_each(|i, v| { keep.push(f(i, v)) });
+        if let PostingList::Encoded{ ref mut ids, ref mut weights } = *self {
+            let retained: Vec<u32> = ids.to_vec().into_iter().zip(&keep).filter(|&(_, &keep)| { keep }).map(|(i, _)| { i }).collect();
+            *ids = PostingIds::new(&retained, ids.is_compressed());
+            weights.retain(&keep);
+        }
+    }
+
+    /// retained_size returns the number of the postings for which f returns true, and memory_size after retain with f.
+    pub fn retained_size<F: FnMut(u32, f32) -> bool>(&self, mut f: F) -> (usize, usize) {
+        let nquantized = match *self {
+            PostingList::Encoded{ weights: PostingWeights::U8{ ref values, .. }, .. } => values.len(),
+            _ => self.len(),
+        };
+        let (mut n, mut nexact, mut varint_size, mut last, mut k) = (0, 0, 0, 0, 0);
+        self.for_each(|i, v| {
+            if f(i, v) {
+                varint_size += varint_len(i - last);
+                last = i;
+                n += 1;
+                if k >= nquantized {
+                    nexact += 1;
+                }
+            }
+            k += 1;
+        });
+        let size = match *self {
+            PostingList::Plain(_) => n*mem::size_of::<(u32, f32)>(),
+            PostingList::Encoded{ ref ids, ref weights } => {
+                let ids_size = if ids.is_compressed() { varint_size } else { n*mem::size_of::<u32>() };
+                ids_size + match *weights {
+                    PostingWeights::F32(_) => n*mem::size_of::<f32>(),
+                    PostingWeights::F16(_) => n*mem::size_of::<u16>(),
+                    PostingWeights::U8{ .. } => 2*mem::size_of::<f32>() + (n - nexact) + nexact*mem::size_of::<f32>(),
+                }
+            },
+        };
+        (n, size)
+    }
+}
+
+impl From<Vec<(u32, f32)>> for PostingList {
+    fn from(postings: Vec<(u32, f32)>) -> PostingList {
+        PostingList::Plain(Buffer::from(postings))
+    }
+}
+
+/// DENSE_KEYS_RATIO is the maximum ratio of the key space to the number of the keys for the dense rows of CsrPostings.
+pub const DENSE_KEYS_RATIO: usize = 4;
+
+// KeyRows maps the feature keys to the rows of CsrPostings.
+enum KeyRows {
+    // Dense uses the keys as the rows, where the missing keys have the empty rows.
+    Dense,
+    // Sparse remaps the keys to the rows.
+    Sparse(HashMap<u32, u32, BuildHasher>),
+}
+
+/// CsrPostings is the plain posting lists of all the keys stored in one offsets array and one contiguous postings array.
+/// If the keys are dense in 0..D, the rows are looked up by the keys directly, otherwise through a remapping table.
+/// The maximum absolute weight of each row is kept for the dynamic pruning of DatasetIndex::find_nearests.
+pub struct CsrPostings {
+    rows: KeyRows,
+    postings: CsrVectors<(u32, f32)>,
+    max_weights: Vec<f32>,
+}
+
+// max_weights returns the maximum absolute weight of each row of postings, which is 0 for the empty rows.
+fn max_weights(postings: &CsrVectors<(u32, f32)>) -> Vec<f32> {
+    postings.iter().map(|row| { row.iter().fold(0.0f32, |max, &(_, v)| { max.max(v.abs()) }) }).collect()
+}
+
+impl CsrPostings {
+    /// from_sorted returns the postings of the sorted keys, where the k-th row of postings is of keys[k].
+    /// The rows are made dense if the key space is at most DENSE_KEYS_RATIO times the number of the keys.
+    pub fn from_sorted(keys: &[u32], postings: CsrVectors<(u32, f32)>) -> CsrPostings {
+        let nkeys = keys.len();
+        let key_space = keys.last().map(|&key| { key as usize + 1 }).unwrap_or(0);
+        if key_space == nkeys {
+            let max_weights = max_weights(&postings);
+            return CsrPostings{ rows: KeyRows::Dense, postings, max_weights };
+        }
+        if key_space <= DENSE_KEYS_RATIO*nkeys {
+            // The empty rows are inserted for the missing keys, sharing the data of postings.
+            let mut offsets = Vec::with_capacity(key_space + 1);
+            offsets.push(0);
+            for (k, &key) in keys.iter().enumerate() {
+                let start = postings.offsets()[k];
+                offsets.resize(key as usize + 1, start);
+                offsets.push(postings.offsets()[k+1]);
+            }
+            let (_, data) = postings.into_parts();
+            let postings = CsrVectors::from_parts(Buffer::from(offsets), data).unwrap();
+            let max_weights = max_weights(&postings);
+            return CsrPostings{ rows: KeyRows::Dense, postings, max_weights };
+        }
+        let rows = keys.iter().enumerate().map(|(k, &key)| { (key, k as u32) }).collect();
+        let max_weights = max_weights(&postings);
+        CsrPostings{ rows: KeyRows::Sparse(rows), postings, max_weights }
+    }
+
+    /// from_map returns the postings of the plain posting lists in the map.
+    pub fn from_map<V: AsRef<[(u32, f32)]>>(map: &HashMap<u32, V, BuildHasher>) -> CsrPostings {
+        let mut keys: Vec<u32> = map.keys().cloned().collect();
+        keys.sort_unstable();
+        let nnz = map.values().map(|postings| { postings.as_ref().len() }).sum();
+        let mut postings = CsrVectors::with_capacity(keys.len(), nnz);
+        for key in &keys {
+            postings.push(map[key].as_ref());
+        }
+        CsrPostings::from_sorted(&keys, postings)
+    }
+
+    /// is_dense returns true if the rows are looked up by the keys directly.
+    pub fn is_dense(&self) -> bool {
+        matches!(self.rows, KeyRows::Dense)
+    }
+
+    // row returns the row of the key.
+    #[inline]
+    fn row(&self, key: u32) -> Option<usize> {
+        match self.rows {
+            KeyRows::Dense => if (key as usize) < self.postings.len() { Some(key as usize) } else { None },
+            KeyRows::Sparse(ref rows) => rows.get(&key).map(|&row| { row as usize }),
+        }
+    }
+
+    /// get returns the postings of the key.
+    #[inline]
+    pub fn get(&self, key: u32) -> Option<&[(u32, f32)]> {
+        self.row(key).map(|row| { &self.postings[row] })
+    }
+
+    /// get_with_max_weight returns the postings of the key with the maximum absolute weight of them.
+    #[inline]
+    pub fn get_with_max_weight(&self, key: u32) -> Option<(&[(u32, f32)], f32)> {
+        self.row(key).map(|row| { (&self.postings[row], self.max_weights[row]) })
+    }
+
+    /// keys returns the sorted keys having some postings.
+    pub fn keys(&self) -> Vec<u32> {
+        match self.rows {
+            KeyRows::Dense => (0..self.postings.len()).filter(|&key| { !self.postings[key].is_empty() }).map(|key| { key as u32 }).collect(),
+            KeyRows::Sparse(ref rows) => {
+                let mut keys: Vec<u32> = rows.iter().filter(|&(_, &row)| { !self.postings[row as usize].is_empty() }).map(|(&key, _)| { key }).collect();
+                keys.sort_unstable();
+                keys
+            },
+        }
+    }
+
+    /// npostings returns the total number of the postings.
+    pub fn npostings(&self) -> usize {
+        self.postings.nnz()
+    }
+
+    /// memory_size returns the number of the bytes used by the postings, the lookup tables and the maximum weights.
+    pub fn memory_size(&self) -> usize {
+        let remap_size = match self.rows {
+            KeyRows::Dense => 0,
+            KeyRows::Sparse(ref rows) => rows.len()*mem::size_of::<(u32, u32)>(),
+        };
+        mem::size_of_val(self.postings.data()) + mem::size_of_val(self.postings.offsets()) + remap_size + mem::size_of_val(&self.max_weights[..])
+    }
+
+    /// retain keeps only the postings accepted by the predicates in place, and updates the maximum weights.
+    /// predicate is called with the postings of each row, and returns the predicate of the postings of the row.
+    /// The rows becoming empty are kept, so the lookup tables are not changed.
+    pub fn retain<P: Fn(u32, f32) -> bool, F: FnMut(&[(u32, f32)]) -> P>(&mut self, mut predicate: F) {
+        self.postings.retain(|row| {
+            let keep = predicate(row);
+            move |&(i, v): &(u32, f32)| { keep(i, v) }
+        });
+        self.max_weights = max_weights(&self.postings);
+    }
+
+    /// retained_memory_size returns memory_size after retain with the predicate, without retaining the postings.
+    pub fn retained_memory_size<P: Fn(u32, f32) -> bool, F: FnMut(&[(u32, f32)]) -> P>(&self, mut predicate: F) -> usize {
+        let nretained: usize = self.postings.iter().map(|row| {
+            let keep = predicate(row);
+            row.iter().filter(|&&(i, v)| { keep(i, v) }).count()
+        }).sum();
+        self.memory_size() - (self.npostings() - nretained)*mem::size_of::<(u32, f32)>()
+    }
+
+    /// to_map returns the posting list of each key.
+    pub fn to_map(&self) -> HashMap<u32, PostingList, BuildHasher> {
+        self.keys().into_iter().map(|key| { (key, PostingList::from(self.get(key).unwrap().to_vec())) }).collect()
+    }
+}
+
+/// PostingIndex is the posting lists of all the feature keys.
+pub enum PostingIndex {
+    /// Map stores a posting list per key, which may be encoded, and can be updated incrementally.
+    Map(HashMap<u32, PostingList, BuildHasher>),
+    /// Csr stores the plain posting lists contiguously, which is the fastest for the queries.
+    Csr(CsrPostings),
+}
+
+impl PostingIndex {
+    /// keys returns the sorted keys having some postings.
+    pub fn keys(&self) -> Vec<u32> {
+        match *self {
+            PostingIndex::Map(ref map) => {
+                let mut keys: Vec<u32> = map.keys().cloned().collect();
+                keys.sort_unstable();
+                keys
+            },
+            PostingIndex::Csr(ref csr) => csr.keys(),
+        }
+    }
+
+    /// len returns the number of the postings of the key.
+    pub fn len(&self, key: u32) -> usize {
+        match *self {
+            PostingIndex::Map(ref map) => map.get(&key).map(|list| { list.len() }).unwrap_or(0),
+            PostingIndex::Csr(ref csr) => csr.get(key).map(|postings| { postings.len() }).unwrap_or(0),
+        }
+    }
+
+    /// npostings returns the total number of the postings.
+    pub fn npostings(&self) -> usize {
+        match *self {
+            PostingIndex::Map(ref map) => map.values().map(|list| { list.len() }).sum(),
+            PostingIndex::Csr(ref csr) => csr.npostings(),
+        }
+    }
+
+    /// for_each calls f with each posting of the key in the ascending order of the entry ids.
+    #[inline]
+    pub fn for_each<F: FnMut(u32, f32)>(&self, key: u32, mut f: F) {
+        match *self {
+            PostingIndex::Map(ref map) => {
+                if let Some(list) = map.get(&key) {
+                    list.for_each(f);
+                }
+            },
+            PostingIndex::Csr(ref csr) => {
+                if let Some(postings) = csr.get(key) {
+                    for &(i, v) in postings {
+                        f(i, v);
+                    }
+                }
+            },
+        }
+    }
+
+    /// memory_size returns the number of the bytes used by the posting lists.
+    pub fn memory_size(&self) -> usize {
+        match *self {
+            PostingIndex::Map(ref map) => map.values().map(|list| { list.memory_size() }).sum(),
+            PostingIndex::Csr(ref csr) => csr.memory_size(),
+        }
+    }
+
+    /// retain keeps only the postings accepted by the predicates, pruning the posting lists one by one in place.
+    /// predicate is called with the decoded postings of each key, and returns the predicate of the postings of the key.
+    /// The Map posting lists becoming empty are removed, and the Csr ones are kept as CsrPostings::retain.
+    pub fn retain<P: Fn(u32, f32) -> bool, F: FnMut(&[(u32, f32)]) -> P>(&mut self, mut predicate: F) {
+        match *self {
+            PostingIndex::Map(ref mut map) => {
+                let mut postings = Vec::new();
+                for list in map.values_mut() {
+                    postings.clear();
+                    list.for_each(|i, v| { postings.push((i, v)) });
+                    list.retain(predicate(&postings));
+                }
+                map.retain(|_, list| { !list.is_empty() });
+            },
+            PostingIndex::Csr(ref mut csr) => csr.retain(predicate),
+        }
+    }
+
+    /// retained_memory_size returns memory_size after retain with the predicate, without retaining the postings.
+    pub fn retained_memory_size<P: Fn(u32, f32) -> bool, F: FnMut(&[(u32, f32)]) -> P>(&self, mut predicate: F) -> usize {
+        match *self {
+            PostingIndex::Map(ref map) => {
+                let mut postings = Vec::new();
+                map.values().map(|list| {
+                    postings.clear();
+                    list.for_each(|i, v| { postings.push((i, v)) });
+                    match list.retained_size(predicate(&postings)) {
+                        (0, _) => 0,
+                        (_, size) => size,
+                    }
+                }).sum()
+            },
+            PostingIndex::Csr(ref csr) => csr.retained_memory_size(predicate),
+        }
+    }
+
+    /// to_mut returns the map of the posting lists, converting the Csr postings into Map.
+    pub fn to_mut(&mut self) -> &mut HashMap<u32, PostingList, BuildHasher> {
+        if let PostingIndex::Csr(ref csr) = *self {
+            *self = PostingIndex::Map(csr.to_map());
+        }
+        match *self {
+            PostingIndex::Map(ref mut map) => map,
+            PostingIndex::Csr(_) => unreachable!(),
+        }
+    }
+
+    /// freeze converts the Map postings into Csr, if all the posting lists are plain.
+    pub fn freeze(&mut self) {
+        let csr = match *self {
+            PostingIndex::Map(ref map) if map.values().all(|list| { matches!(*list, PostingList::Plain(_)) }) => {
+                let mut keys: Vec<u32> = map.keys().cloned().collect();
+                keys.sort_unstable();
+                let mut postings = CsrVectors::with_capacity(keys.len(), map.values().map(|list| { list.len() }).sum());
+                for key in &keys {
+                    if let PostingList::Plain(ref list) = map[key] {
+                        postings.push(list);
+                    }
+                }
+                CsrPostings::from_sorted(&keys, postings)
+            },
+            _ => return,
+        };
+        *self = PostingIndex::Csr(csr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_encodes_7_bit_groups() {
+        let cases: [(u32, &[u8]); 7] = [
+            (0, &[0x00]), (1, &[0x01]), (127, &[0x7f]), (128, &[0x80, 0x01]),
+            (16383, &[0xff, 0x7f]), (16384, &[0x80, 0x80, 0x01]), (u32::MAX, &[0xff, 0xff, 0xff, 0xff, 0x0f]),
+        ];
+        for &(x, expected) in &cases {
+            let mut bytes = Vec::new();
+            push_varint(&mut bytes, x);
+            assert_eq!(bytes, expected, "{}", x);
+            assert_eq!(varint_len(x), expected.len());
+        }
+    }
+
+    #[test]
+    fn varint_ids_round_trip() {
+        // The deltas are at the boundaries of the 7-bit groups, and the first id is not 0.
+        let mut ids = vec![5];
+        for &delta in &[0, 127, 128, 16383, 16384, 1] {
+            let last = *ids.last().unwrap();
+            ids.push(last + delta);
+        }
+        ids.push(u32::MAX);
+        let compressed = PostingIds::new(&ids, true);
+        assert!(compressed.is_compressed());
+        assert_eq!(compressed.len(), ids.len());
+        assert_eq!(compressed.to_vec(), ids);
+        let deltas: Vec<u32> = ids.iter().scan(0, |last, &i| { let delta = i - *last; *last = i; Some(delta) }).collect();
+        assert_eq!(compressed.memory_size(), deltas.iter().map(|&delta| { varint_len(delta) }).sum::<usize>());
+        for &first in &[0, 127, 128, 16384, u32::MAX] {
+            assert_eq!(PostingIds::new(&[first], true).to_vec(), [first]);
+        }
+        // The postings are decoded with their weights.
+        let postings: Vec<(u32, f32)> = ids.iter().enumerate().map(|(k, &i)| { (i, k as f32 - 2.5) }).collect();
+        let mut list = PostingList::encode(&postings[..4], true, WeightEncoding::F32);
+        for &(i, v) in &postings[4..] {
+            list.push(i, v);
+        }
+        assert_eq!(list.to_vec(), postings);
+        assert_eq!(list.retained_size(|_, _| { true }), (list.len(), list.memory_size()));
+    }
+
+    #[test]
+    fn f32_to_f16_rounds_to_nearest_even() {
+        let cases = [
+            (0.0, 0x0000), (-0.0, 0x8000), (1.0, 0x3c00), (-2.0, 0xc000), (0.1, 0x2e66), (-0.1, 0xae66),
+            // The largest finite value, and the overflows into the infinities.
+            (65504.0, 0x7bff), (65519.0, 0x7bff), (65520.0, 0x7c00), (1e6, 0x7c00), (-1e6, 0xfc00), (f32::INFINITY, 0x7c00),
+            // The smallest normal value, the subnormals, and the underflows into the zeros.
+            (6.1035156e-5, 0x0400), (6.097555e-5, 0x03ff), (5.9604645e-8, 0x0001), (-5.9604645e-8, 0x8001),
+            (8.940697e-8, 0x0002), (2.9802322e-8, 0x0000), (-1e-10, 0x8000),
+        ];
+        for &(x, h) in &cases {
+            assert_eq!(f32_to_f16(x), h, "{:e}", x);
+        }
+        assert_eq!(f32_to_f16(f32::NAN) & 0x7c00, 0x7c00);
+        assert_ne!(f32_to_f16(f32::NAN) & 0x3ff, 0);
+    }
+
+    #[test]
+    fn f16_to_f32_is_exact() {
+        assert_eq!(f16_to_f32(0x0000).to_bits(), 0.0f32.to_bits());
+        assert_eq!(f16_to_f32(0x8000).to_bits(), (-0.0f32).to_bits());
+        assert_eq!(f16_to_f32(0x7bff), 65504.0);
+        assert_eq!(f16_to_f32(0xfbff), -65504.0);
+        assert_eq!(f16_to_f32(0x0001), 2.0f32.powi(-24));
+        assert_eq!(f16_to_f32(0x03ff), 1023.0*2.0f32.powi(-24));
+        assert_eq!(f16_to_f32(0x0400), 2.0f32.powi(-14));
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+        assert_eq!(f16_to_f32(0xfc00), f32::NEG_INFINITY);
+        assert!(f16_to_f32(0x7e00).is_nan());
+        // Every half-precision float except NaN goes back to itself.
+        for h in (0..=0xffffu32).map(|h| { h as u16 }).filter(|&h| { h & 0x7fff <= 0x7c00 }) {
+            assert_eq!(f32_to_f16(f16_to_f32(h)), h, "{:#06x}", h);
+        }
+    }
+
+    // assert_quantized asserts that the U8 weights are within the half quantization step from the weights.
+    fn assert_quantized(quantized: &PostingWeights, weights: &[f32]) {
+        let (min, scale) = match *quantized {
+            PostingWeights::U8{ min, scale, .. } => (min, scale),
+            _ => panic!("not quantized into U8"),
+        };
+        let decoded = quantized.to_vec();
+        assert_eq!(decoded.len(), weights.len());
+        for (&actual, &expected) in decoded.iter().zip(weights) {
+            assert!((actual - expected).abs() <= scale/2.0 + 1e-6, "{} != {} (min {}, scale {})", actual, expected, min, scale);
+        }
+    }
+
+    #[test]
+    fn u8_weights_are_quantized_between_min_and_max() {
+        let weights = [0.5, -0.25, 1.0, 0.125, 0.3333, -0.25];
+        let quantized = PostingWeights::new(&weights, WeightEncoding::U8);
+        match quantized {
+            PostingWeights::U8{ min, scale, ref values, .. } => {
+                assert_eq!((min, scale), (-0.25, 1.25/255.0));
+                assert_eq!((values[1], values[2]), (0, 255));
+            },
+            _ => panic!("not quantized into U8"),
+        }
+        assert_quantized(&quantized, &weights);
+        assert_eq!(quantized.memory_size(), 8 + weights.len());
+    }
+
+    #[test]
+    fn u8_weights_of_equal_values_are_exact() {
+        let weights = [0.75; 5];
+        let quantized = PostingWeights::new(&weights, WeightEncoding::U8);
+        assert!(matches!(quantized, PostingWeights::U8{ min, scale, .. } if min == 0.75 && scale == 0.0));
+        assert_eq!(quantized.to_vec(), weights);
+        let empty = PostingWeights::new(&[], WeightEncoding::U8);
+        assert!(matches!(empty, PostingWeights::U8{ min, scale, .. } if min == 0.0 && scale == 0.0));
+        assert!(empty.to_vec().is_empty());
+    }
+
+    #[test]
+    fn u8_push_never_requantizes() {
+        let mut weights = vec![0.2, 0.4, 0.6];
+        let mut list = PostingList::encode(&[(0, 0.2), (1, 0.4), (2, 0.6)], true, WeightEncoding::U8);
+        let initial = list.to_vec();
+        // The weights in the range are quantized, and the others are kept exact with all the following ones.
+        for (k, &v) in [0.5, 0.9, 0.1, 0.3, 0.45].iter().enumerate() {
+            list.push(3 + k as u32, v);
+            weights.push(v);
+        }
+        let decoded = list.to_vec();
+        assert_eq!(&decoded[..3], &initial[..]);
+        if let PostingList::Encoded{ weights: ref quantized, .. } = list {
+            assert_quantized(quantized, &weights);
+            assert_eq!(quantized.memory_size(), 8 + 4 + 4*4);
+        }
+        assert_eq!(&decoded[4..], &[(4, 0.9), (5, 0.1), (6, 0.3), (7, 0.45)]);
+        // Many pushes out of the range do not move the quantized weights.
+        for k in 0..100 {
+            list.push(8 + k, 1.0 + k as f32);
+        }
+        assert_eq!(&list.to_vec()[..8], &decoded[..]);
+        // The retained weights are not encoded again.
+        let (n, size) = list.retained_size(|i, _| { i % 2 == 0 });
+        list.retain(|i, _| { i % 2 == 0 });
+        let retained = list.to_vec();
+        let expected: Vec<_> = decoded.iter().cloned().chain((0..100).map(|k| { (8 + k, 1.0 + k as f32) })).filter(|&(i, _)| { i % 2 == 0 }).collect();
+        assert_eq!(retained, expected);
+        assert_eq!((n, size), (list.len(), list.memory_size()));
+    }
+}
@@ -0,0 +1,248 @@
+#![allow(non_snake_case)]
+
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+
+use cache::{load_index_binary,write_index_binary};
+use dataset::{Dataset,DatasetError,DatasetErrorKind};
+use nearest::{DatasetIndex,DatasetIndexContext,IndexOptions,NeighborIndex,PruneMethod,PruneSummary,PruningStats,ScanStrategy};
+use postings::WeightEncoding;
+use topk::top_k;
+
+/// ShardedIndex is the training set index split into the shards of the contiguous entry ranges.
+/// Each shard is a DatasetIndex of its own entries, whose entry ids are counted from the start of its range.
+/// The shards are queried in turn with a single context sized to the largest shard, and the top-S of the shards are merged.
+/// Hence, the neighbors are exactly the same as the ones of the unsharded index.
+pub struct ShardedIndex<'a> {
+    shards: Vec<DatasetIndex<'a>>,
+    // starts is the global entry id of the first entry of each shard, followed by the number of the entries.
+    starts: Vec<u32>,
+}
+
+/// ShardedIndexContext is the context shared by the shards in ShardedIndex::find_nearests.
+pub struct ShardedIndexContext {
+    ctx: DatasetIndexContext,
+    candidates: Vec<(u32, f32)>,
+}
+
+impl<'a> ShardedIndex<'a> {
+    /// new returns the index of ds split into the shards of shard_size entries, where 0 means a single shard.
+    /// The single shard borrows the label vectors of ds as DatasetIndex::with_options, and the other shards own theirs.
+    pub fn new(ds: &'a Dataset, shard_size: usize, opts: &IndexOptions) -> ShardedIndex<'a> {
+        if shard_size == 0 || shard_size >= ds.size() {
+            return ShardedIndex::from_shards(vec![DatasetIndex::with_options(ds, opts)]);
+        }
+        let shards = (0..ds.size()).step_by(shard_size).map(|start| {
+            DatasetIndex::with_range(ds, start..(start + shard_size).min(ds.size()), opts)
+        }).collect();
+        ShardedIndex::from_shards(shards)
+    }
+
+    /// from_shards returns the index consisting of the shards in the order of their entry ranges.
+    pub fn from_shards(shards: Vec<DatasetIndex<'a>>) -> ShardedIndex<'a> {
+        let mut starts = Vec::with_capacity(shards.len() + 1);
+        starts.push(0);
+        for shard in &shards {
+            let start = starts[starts.len()-1];
+            starts.push(start + shard.size() as u32);
+        }
+        ShardedIndex{ shards, starts }
+    }
+
+    pub fn shards(&self) -> &[DatasetIndex<'a>] {
+        &self.shards
+    }
+
+    /// into_shards returns the shards in the order of their entry ranges.
+    pub fn into_shards(self) -> Vec<DatasetIndex<'a>> {
+        self.shards
+    }
+
+    /// nshards returns the number of the shards.
+    pub fn nshards(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn size(&self) -> usize {
+        self.starts[self.shards.len()] as usize
+    }
+
+    // locate returns the shard of the global entry id i, and the entry id in the shard.
+    fn locate(&self, i: u32) -> (usize, u32) {
+        let k = self.starts.partition_point(|&start| { start <= i }) - 1;
+        (k, i - self.starts[k])
+    }
+
+    /// compress compresses the posting lists of each shard with DatasetIndex::compress.
+    pub fn compress(&mut self) {
+        for shard in &mut self.shards {
+            shard.compress();
+        }
+    }
+
+    /// quantize quantizes the weights of each shard with DatasetIndex::quantize.
+    pub fn quantize(&mut self, encoding: WeightEncoding) {
+        for shard in &mut self.shards {
+            shard.quantize(encoding);
+        }
+    }
+
+    /// prune prunes each shard with DatasetIndex::prune, and returns the total summary.
+    pub fn prune(&mut self, method: PruneMethod, level: f32) -> PruneSummary {
+        let summaries: Vec<_> = self.shards.iter_mut().map(|shard| { shard.prune(method, level) }).collect();
+        merge_summaries(method, &summaries)
+    }
+
+    /// prune_to_budget prunes each shard with DatasetIndex::prune_to_budget, dividing the budget in proportion to the memory sizes of the shards.
+    /// The level of the returned summary is the maximum level of the shards.
+    /// This returns None, if some shards cannot fit into their budgets, where the other shards may be pruned.
+    pub fn prune_to_budget(&mut self, method: PruneMethod, budget: usize) -> Option<PruneSummary> {
+        let memory_size = self.memory_size().max(1);
+        let mut summaries = Vec::with_capacity(self.shards.len());
+        for shard in &mut self.shards {
+            let shard_budget = ((budget as f64)*(shard.memory_size() as f64)/(memory_size as f64)) as usize;
+            summaries.push(shard.prune_to_budget(method, shard_budget)?);
+        }
+        Some(merge_summaries(method, &summaries))
+    }
+
+    /// set_scan_strategy sets the scan strategy of each shard.
+    pub fn set_scan_strategy(&mut self, strategy: ScanStrategy) {
+        for shard in &mut self.shards {
+            shard.set_scan_strategy(strategy);
+        }
+    }
+
+    /// memory_size returns the total number of the bytes used by the posting lists of the shards.
+    pub fn memory_size(&self) -> usize {
+        self.shards.iter().map(|shard| { shard.memory_size() }).sum()
+    }
+
+    /// pruning_stats returns the total PruningStats of the shards, where each query is counted for each shard.
+    pub fn pruning_stats(&self) -> PruningStats {
+        self.shards.iter().map(|shard| { shard.pruning_stats() }).fold(PruningStats::default(), |total, stats| {
+            PruningStats{
+                nqueries: total.nqueries + stats.nqueries,
+                npostings: total.npostings + stats.npostings,
+                nskipped: total.nskipped + stats.nskipped,
+            }
+        })
+    }
+}
+
+// merge_summaries returns the total summary of the shards.
+fn merge_summaries(method: PruneMethod, summaries: &[PruneSummary]) -> PruneSummary {
+    PruneSummary{
+        method,
+        level: summaries.iter().fold(0.0f32, |level, summary| { level.max(summary.level) }),
+        npostings_before: summaries.iter().map(|summary| { summary.npostings_before }).sum(),
+        npostings_after: summaries.iter().map(|summary| { summary.npostings_after }).sum(),
+        memory_size_before: summaries.iter().map(|summary| { summary.memory_size_before }).sum(),
+        memory_size_after: summaries.iter().map(|summary| { summary.memory_size_after }).sum(),
+    }
+}
+
+impl<'a> NeighborIndex for ShardedIndex<'a> {
+    type Context = ShardedIndexContext;
+
+    // The context of the largest shard is large enough for any shard, so each thread holds O(shard_size) rather than O(N).
+    fn new_context(&self) -> ShardedIndexContext {
+        let largest = self.shards.iter().max_by_key(|shard| { shard.size() }).expect("no shards");
+        ShardedIndexContext{
+            ctx: largest.new_context(),
+            candidates: Vec::new(),
+        }
+    }
+
+    // The top-S of the whole index is in the union of the top-S of the shards,
+    // because the entry ids in each shard are in the same order as the global ones.
+    fn find_nearests(&self, xi: &[(u32, f32)], S: usize, beta: f32, ctx: &mut ShardedIndexContext) -> Vec<(u32, f32)> {
+        for (k, shard) in self.shards.iter().enumerate() {
+            let start = self.starts[k];
+            ctx.candidates.extend(shard.find_nearests(xi, S, beta, &mut ctx.ctx).into_iter().map(|(i, sim)| { (start + i, sim) }));
+        }
+        let index_sims = top_k(&mut ctx.candidates, S);
+        ctx.candidates.clear();
+        index_sims
+    }
+
+    fn labels(&self, j: u32) -> &[u32] {
+        let (k, i) = self.locate(j);
+        self.shards[k].labels(i)
+    }
+}
+
+/// shard_path returns the path of the k-th shard in the directory written by write_shards.
+pub fn shard_path<P: AsRef<Path>>(dirname: P, k: usize) -> PathBuf {
+    dirname.as_ref().join(format!("shard-{:05}.bin", k))
+}
+
+/// write_shards writes each shard with cache::write_index_binary into the directory, which is created if missing.
+/// Each shard file can be loaded independently with cache::load_index_binary.
+pub fn write_shards<P: AsRef<Path>>(dirname: P, index: &ShardedIndex) -> io::Result<()> {
+    let dirname = dirname.as_ref();
+    fs::create_dir_all(dirname)?;
+    for (k, shard) in index.shards().iter().enumerate() {
+        write_index_binary(shard_path(dirname, k), shard)?;
+    }
+    // The stale shards of the previous larger index are removed.
+    let mut k = index.nshards();
+    while shard_path(dirname, k).exists() {
+        fs::remove_file(shard_path(dirname, k))?;
+        k += 1;
+    }
+    Ok(())
+}
+
+/// load_shards loads the shards written by write_shards in the order of their entry ranges.
+pub fn load_shards<P: AsRef<Path>>(dirname: P) -> Result<ShardedIndex<'static>, DatasetError> {
+    let dirname = dirname.as_ref();
+    let mut shards = Vec::new();
+    while shard_path(dirname, shards.len()).exists() {
+        let path = shard_path(dirname, shards.len());
+        shards.push(load_index_binary(&path).map_err(|e| { e.with_path(&path) })?);
+    }
+    if shards.is_empty() {
+        let e = io::Error::new(io::ErrorKind::NotFound, "no shards");
+        return Err(DatasetError::new(0, 0, DatasetErrorKind::Io(e)).with_path(dirname));
+    }
+    Ok(ShardedIndex::from_shards(shards))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::random_dataset;
+
+    #[test]
+    fn context_is_bounded_by_largest_shard() {
+        let ds = random_dataset(100, 30, 10, 1);
+        for &(shard_size, largest) in &[(1, 1), (7, 7), (30, 30), (64, 64), (100, 100), (0, 100)] {
+            let index = ShardedIndex::new(&ds, shard_size, &IndexOptions::default());
+            assert_eq!(index.new_context().ctx.capacity(), largest, "shard_size={}", shard_size);
+        }
+    }
+
+    #[test]
+    fn sharded_find_nearests_matches_unsharded() {
+        let ds = random_dataset(100, 30, 10, 2);
+        let queries = random_dataset(40, 30, 10, 3);
+        let exact = DatasetIndex::new(&ds);
+        let mut exact_ctx = exact.new_context();
+        for &shard_size in &[1, 3, 7, 32, 99, 100] {
+            let index = ShardedIndex::new(&ds, shard_size, &IndexOptions::default());
+            let mut ctx = index.new_context();
+            for (xi, _) in &queries {
+                for &S in &[1, 5, 10, 200] {
+                    for &beta in &[0.0, 0.5] {
+                        let expected = exact.find_nearests(xi, S, beta, &mut exact_ctx);
+                        let actual = index.find_nearests(xi, S, beta, &mut ctx);
+                        assert_eq!(actual, expected, "shard_size={} S={} beta={}", shard_size, S, beta);
+                        assert_eq!(index.vote_labels(&actual, 1.0, 5, 1.0), exact.vote_labels(&expected, 1.0, 5, 1.0));
+                    }
+                }
+            }
+        }
+    }
+}
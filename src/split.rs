@@ -0,0 +1,316 @@
+use std::fs::File;
+use std::io::{self,BufRead,BufWriter,Write};
+use std::path::{Path,PathBuf};
+use std::str::FromStr;
+
+use dataset::{Dataset,DatasetError,DatasetErrorKind,Dialect,FeatureVector,LabelVector,ReadOptions,ReadSummary,WriteOptions,open_dataset,parse_features};
+
+// The split-matrix format of the Extreme Classification Repository stores the features and the labels in two files.
+// Each file is a sparse matrix consisting of the header line `rows cols` and the rows of `col:value` words.
+// The values in the label matrix are ignored in reading, and written as 1.
+
+/// SparseMatrixHeader is the header line `rows cols` of a sparse matrix file.
+#[derive(Clone,Copy,Debug,Default,Eq,PartialEq)]
+pub struct SparseMatrixHeader {
+    pub nrows: usize,
+    pub ncols: usize,
+}
+
+impl FromStr for SparseMatrixHeader {
+    type Err = DatasetErrorKind;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace().map(|word| { word.parse::<usize>() });
+        match (words.next(), words.next(), words.next()) {
+            (Some(Ok(nrows)), Some(Ok(ncols)), None) => Ok(SparseMatrixHeader{ nrows, ncols }),
+            _ => Err(DatasetErrorKind::BadHeader(s.to_string())),
+        }
+    }
+}
+
+struct SparseMatrixReader<R> {
+    path: PathBuf,
+    lines: io::Lines<R>,
+    lineno: usize,
+    header: Option<SparseMatrixHeader>,
+    dialect: Dialect,
+    is_label: bool,
+}
+
+impl<R: BufRead> SparseMatrixReader<R> {
+    fn new(reader: R, path: &Path, headerless: bool, dialect: &Dialect, is_label: bool) -> Result<SparseMatrixReader<R>, DatasetError> {
+        let mut lines = reader.lines();
+        let header = if headerless {
+            None
+        } else {
+            let line = match lines.next() {
+                Some(line) => line.map_err(|e| { DatasetError::new(1, 0, DatasetErrorKind::Io(e)).with_path(path) })?,
+                None => String::new(),
+            };
+            Some(line.parse::<SparseMatrixHeader>().map_err(|kind| { DatasetError::new(1, 1, kind).with_path(path) })?)
+        };
+        Ok(SparseMatrixReader{
+            path: path.to_path_buf(),
+            lines,
+            lineno: if headerless { 0 } else { 1 },
+            header,
+            dialect: dialect.clone(),
+            is_label,
+        })
+    }
+
+    // read_row reads the next row into the buffer, and returns false at the end.
+    // The comments are stripped, but the blank lines are the empty rows, because the rows of the two matrices are zipped.
+    fn read_row(&mut self, row: &mut FeatureVector) -> Result<bool, DatasetError> {
+        row.clear();
+        let line = match self.lines.next() {
+            Some(line) => line,
+            None => return Ok(false),
+        };
+        self.lineno += 1;
+        let line = line.map_err(|e| { DatasetError::new(self.lineno, 0, DatasetErrorKind::Io(e)).with_path(&self.path) })?;
+        let ncols = self.header.map(|header| { header.ncols });
+        let row_line = match line.find('#') {
+            Some(sharp) if self.dialect.comments => &line[..sharp],
+            _ => &line[..],
+        };
+        let words: Box<dyn Iterator<Item = &str>> = if self.dialect.tolerant {
+            Box::new(row_line.split_whitespace())
+        } else {
+            Box::new(row_line.split(' ').filter(|word| { !word.is_empty() }))
+        };
+        parse_features(&line, words, self.lineno, ncols, &self.dialect, row).map_err(|mut e| {
+            if self.is_label {
+                e.kind = match e.kind {
+                    DatasetErrorKind::BadFeatureKey(s) => DatasetErrorKind::BadLabel(s),
+                    DatasetErrorKind::FeatureKeyOutOfRange(label) => DatasetErrorKind::LabelOutOfRange(label),
+                    kind => kind,
+                };
+            }
+            e.with_path(&self.path)
+        })?;
+        Ok(true)
+    }
+}
+
+/// SplitDatasetReader reads the entries of a dataset in the split-matrix format one by one.
+/// As an iterator, this yields the owned entries, and stops after the first error.
+pub struct SplitDatasetReader<R> {
+    xreader: SparseMatrixReader<R>,
+    yreader: SparseMatrixReader<R>,
+    opts: ReadOptions,
+    ybuf: FeatureVector,
+    nentries: usize,
+    nskipped: usize,
+    done: bool,
+}
+
+impl SplitDatasetReader<Box<dyn BufRead>> {
+    /// open returns the reader of the feature and label matrix files opened with open_dataset.
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(features_path: P, labels_path: Q, opts: &ReadOptions) -> Result<SplitDatasetReader<Box<dyn BufRead>>, DatasetError> {
+        let (features_path, labels_path) = (features_path.as_ref(), labels_path.as_ref());
+        let xreader = open_dataset(features_path).map_err(|e| { DatasetError::new(0, 0, DatasetErrorKind::Io(e)).with_path(features_path) })?;
+        let yreader = open_dataset(labels_path).map_err(|e| { DatasetError::new(0, 0, DatasetErrorKind::Io(e)).with_path(labels_path) })?;
+        SplitDatasetReader::new(xreader, features_path, yreader, labels_path, opts)
+    }
+}
+
+impl<R: BufRead> SplitDatasetReader<R> {
+    /// new returns the reader zipping the feature and label matrices, reading their headers unless opts.headerless.
+    /// The feature keys are shifted by the index base of opts.dialect, but the labels are not, as in the line format.
+    /// The paths are used only for locating the errors.
+    pub fn new(xreader: R, features_path: &Path, yreader: R, labels_path: &Path, opts: &ReadOptions) -> Result<SplitDatasetReader<R>, DatasetError> {
+        let label_dialect = Dialect{ feature_base: 0, ..opts.dialect.clone() };
+        let xreader = SparseMatrixReader::new(xreader, features_path, opts.headerless, &opts.dialect, false)?;
+        let yreader = SparseMatrixReader::new(yreader, labels_path, opts.headerless, &label_dialect, true)?;
+        if let (Some(xheader), Some(yheader)) = (xreader.header, yreader.header) {
+            if xheader.nrows != yheader.nrows {
+                let kind = DatasetErrorKind::EntryCountMismatch{ expected: xheader.nrows, actual: yheader.nrows };
+                return Err(DatasetError::new(1, 1, kind).with_path(labels_path));
+            }
+        }
+        Ok(SplitDatasetReader{
+            xreader,
+            yreader,
+            opts: opts.clone(),
+            ybuf: FeatureVector::new(),
+            nentries: 0,
+            nskipped: 0,
+            done: false,
+        })
+    }
+
+    /// feature_header returns the header of the feature matrix, or None if the reader is headerless.
+    pub fn feature_header(&self) -> Option<&SparseMatrixHeader> {
+        self.xreader.header.as_ref()
+    }
+
+    /// label_header returns the header of the label matrix, or None if the reader is headerless.
+    pub fn label_header(&self) -> Option<&SparseMatrixHeader> {
+        self.yreader.header.as_ref()
+    }
+
+    /// summary returns the summary of the entries read so far.
+    pub fn summary(&self) -> ReadSummary {
+        ReadSummary{ nskipped: self.nskipped }
+    }
+
+    /// read_entry reads the next entry into the given buffers, and returns false at the end.
+    /// At the end, the entry count is checked against the headers.
+    pub fn read_entry(&mut self, xi: &mut FeatureVector, yi: &mut LabelVector) -> Result<bool, DatasetError> {
+        if self.done {
+            return Ok(false);
+        }
+        let result = self.next_entry(xi, yi);
+        match result {
+            Ok(true) => {},
+            _ => self.done = true,
+        }
+        result
+    }
+
+    fn next_entry(&mut self, xi: &mut FeatureVector, yi: &mut LabelVector) -> Result<bool, DatasetError> {
+        loop {
+            let xresult = self.xreader.read_row(xi);
+            let yresult = self.yreader.read_row(&mut self.ybuf);
+            match (xresult, yresult) {
+                (Ok(true), Ok(true)) => {
+                    yi.clear();
+                    yi.extend(self.ybuf.iter().map(|&(label, _)| { label }));
+                    self.nentries += 1;
+                    return Ok(true);
+                },
+                (Ok(false), Ok(false)) => break,
+                (Ok(xmore), Ok(_)) => {
+                    // One of the matrices ended earlier than the other.
+                    let (nrows, reader) = if xmore { (self.yreader.lineno, &self.yreader) } else { (self.xreader.lineno, &self.xreader) };
+                    let kind = DatasetErrorKind::EntryCountMismatch{ expected: self.nentries + self.nskipped + 1, actual: self.nentries + self.nskipped };
+                    return Err(DatasetError::new(nrows, 0, kind).with_path(&reader.path));
+                },
+                (Err(e), _) | (_, Err(e)) => {
+                    if matches!(e.kind, DatasetErrorKind::Io(_)) || !self.opts.skip_bad_lines {
+                        return Err(e);
+                    }
+                    debug!("skipped the malformed row: {}", e);
+                    self.nskipped += 1;
+                },
+            }
+        }
+        if let Some(header) = self.xreader.header {
+            let nentries = self.nentries + self.nskipped;
+            if nentries != header.nrows {
+                let kind = DatasetErrorKind::EntryCountMismatch{ expected: header.nrows, actual: nentries };
+                let e = DatasetError::new(self.xreader.lineno, 0, kind).with_path(&self.xreader.path);
+                if self.opts.strict_header {
+                    return Err(e);
+                }
+                warn!("{}", e);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl<R: BufRead> Iterator for SplitDatasetReader<R> {
+    type Item = Result<(FeatureVector, LabelVector), DatasetError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (mut xi, mut yi) = (FeatureVector::new(), LabelVector::new());
+        match self.read_entry(&mut xi, &mut yi) {
+            Ok(true) => Some(Ok((xi, yi))),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// read_split_dataset reads the dataset from the feature matrix file (e.g. trn_X_Xf.txt) and the label matrix file (e.g. trn_X_Y.txt).
+/// See open_dataset for the supported compressions and the standard input.
+pub fn read_split_dataset<P: AsRef<Path>, Q: AsRef<Path>>(features_path: P, labels_path: Q, opts: &ReadOptions) -> Result<(Dataset, ReadSummary), DatasetError> {
+    let mut reader = SplitDatasetReader::open(features_path, labels_path, opts)?;
+    let mut ds = Dataset::new();
+    if let (Some(xheader), Some(yheader)) = (reader.feature_header(), reader.label_header()) {
+        ds.X.try_reserve(xheader.nrows);
+        ds.Y.try_reserve(yheader.nrows);
+        ds.nfeatures = xheader.ncols;
+        ds.nlabels = yheader.ncols;
+    }
+    let (mut xi, mut yi) = (FeatureVector::new(), LabelVector::new());
+    while reader.read_entry(&mut xi, &mut yi)? {
+        ds.push(&xi, &yi);
+    }
+    Ok((ds, reader.summary()))
+}
+
+/// write_split_dataset writes the dataset into the feature matrix file and the label matrix file.
+/// read_split_dataset reads back the same dataset written by this.
+pub fn write_split_dataset<P: AsRef<Path>, Q: AsRef<Path>>(features_path: P, labels_path: Q, ds: &Dataset, opts: &WriteOptions) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(features_path)?);
+    writeln!(w, "{} {}", ds.size(), ds.nfeatures)?;
+    for xi in &ds.X {
+        for (k, &(key, value)) in xi.iter().enumerate() {
+            if k > 0 {
+                write!(w, " ")?;
+            }
+            match opts.precision {
+                Some(precision) => write!(w, "{}:{:.*}", key, precision, value)?,
+                None => write!(w, "{}:{}", key, value)?,
+            }
+        }
+        writeln!(w)?;
+    }
+    w.flush()?;
+    let mut w = BufWriter::new(File::create(labels_path)?);
+    writeln!(w, "{} {}", ds.size(), ds.nlabels)?;
+    for yi in &ds.Y {
+        for (k, &label) in yi.iter().enumerate() {
+            if k > 0 {
+                write!(w, " ")?;
+            }
+            write!(w, "{}:1", label)?;
+        }
+        writeln!(w)?;
+    }
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use testutil::{random_dataset,temp_path};
+
+    #[test]
+    fn write_split_dataset_round_trips() {
+        let mut ds = random_dataset(30, 20, 5, 9);
+        ds.push(&[], &[1, 3]);
+        ds.push(&[(2, 0.5), (19, -1.25)], &[]);
+        ds.push(&[], &[]);
+        let (features_path, labels_path) = (temp_path("split-X.txt"), temp_path("split-Y.txt"));
+        write_split_dataset(&features_path, &labels_path, &ds, &WriteOptions::default()).unwrap();
+        let (actual, summary) = read_split_dataset(&features_path, &labels_path, &ReadOptions::default()).unwrap();
+        fs::remove_file(&features_path).unwrap();
+        fs::remove_file(&labels_path).unwrap();
+        assert_eq!(actual, ds);
+        assert_eq!(summary.nskipped, 0);
+    }
+
+    fn read_split_from(xtext: &str, ytext: &str, opts: &ReadOptions) -> Result<Vec<(FeatureVector, LabelVector)>, DatasetError> {
+        SplitDatasetReader::new(xtext.as_bytes(), Path::new("X"), ytext.as_bytes(), Path::new("Y"), opts)?.collect()
+    }
+
+    #[test]
+    fn split_dataset_reader_uses_dialect() {
+        let opts = ReadOptions::libsvm();
+        let entries = read_split_from("1:0.5 3:2  # comment\n\n2:1\n", "0:1\n1:1 2:1\n\n", &opts).unwrap();
+        assert_eq!(entries, vec![
+            (vec![(0, 0.5), (2, 2.0)], vec![0]),
+            (vec![], vec![1, 2]),
+            (vec![(1, 1.0)], vec![]),
+        ]);
+        // The feature key 0 is out of the 1-origin index base.
+        let e = read_split_from("0:0.5\n", "0:1\n", &opts).unwrap_err();
+        assert!(matches!(e.kind, DatasetErrorKind::BadFeatureKey(ref s) if s == "0"));
+        assert_eq!((e.line, e.column), (1, 1));
+    }
+}
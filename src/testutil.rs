@@ -0,0 +1,60 @@
+// testutil is the deterministic synthetic datasets shared by the tests.
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+use dataset::Dataset;
+use hash::hash_u32;
+use nearest::{DatasetIndex,NeighborIndex,RecallMeter};
+
+// random_dataset returns n entries drawn from the seed, whose features are in 0..nfeatures and labels are in 0..nlabels.
+// The weights are multiples of 1/4 so that the similarities tie often, and some entries have no features or no labels.
+pub fn random_dataset(n: usize, nfeatures: u32, nlabels: u32, seed: u32) -> Dataset {
+    let mut ds = Dataset::new();
+    let mut h = hash_u32(seed);
+    let mut next = || {
+        h = hash_u32(h);
+        h
+    };
+    for _ in 0..n {
+        let mut xi: Vec<(u32, f32)> = Vec::new();
+        for _ in 0..next()%8 {
+            let key = next()%nfeatures;
+            let value = ((next()%4 + 1) as f32)/4.0;
+            if !xi.iter().any(|&(k, _)| { k == key }) {
+                xi.push((key, value));
+            }
+        }
+        xi.sort_by_key(|&(key, _)| { key });
+        let mut yi: Vec<u32> = (0..next()%3).map(|_| { next()%nlabels }).collect();
+        yi.sort_unstable();
+        yi.dedup();
+        ds.push(&xi, &yi);
+    }
+    ds
+}
+
+// temp_path returns the path of the named temporary file unique to the test process.
+pub fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("rusty-sticker-test-{}-{}", process::id(), name))
+}
+
+// recall returns the recall of the top-S neighbors found by the approximate index of ds against DatasetIndex for the queries.
+// This also asserts that the approximate index finds the neighbors with their exact similarities and votes their labels as DatasetIndex.
+#[allow(non_snake_case)]
+pub fn recall<N: NeighborIndex>(index: &N, ds: &Dataset, queries: &Dataset, S: usize, beta: f32) -> f32 {
+    let exact = DatasetIndex::new(ds);
+    let meter = RecallMeter::new(index, &exact);
+    let (mut ctx, mut exact_ctx) = (meter.new_context(), exact.new_context());
+    for (xi, _) in queries {
+        let index_sims = meter.find_nearests(xi, S, beta, &mut ctx);
+        assert!(index_sims.len() <= S);
+        let all_sims = exact.find_nearests(xi, ds.size(), beta, &mut exact_ctx);
+        for near in &index_sims {
+            assert!(all_sims.contains(near), "{:?} is not found by DatasetIndex", near);
+        }
+        assert_eq!(meter.vote_labels(&index_sims, 1.0, 5, 1.0), exact.vote_labels(&index_sims, 1.0, 5, 1.0));
+    }
+    meter.recall()
+}
@@ -0,0 +1,95 @@
+#![allow(non_snake_case)]
+
+/// ranks_before returns true if the (id, score) pair a ranks before b.
+/// The larger score ranks before, and the ties are broken by the smaller id, so the ranking is a total order independent of the input order.
+pub fn ranks_before(a: &(u32, f32), b: &(u32, f32)) -> bool {
+    a.1 > b.1 || (a.1 == b.1 && a.0 < b.0)
+}
+
+/// sort_largests_with_heap moves the K highest-ranked entries to the head of kcs, sorted in the ranking order.
+/// The order of the rest entries is unspecified.
+/// precedes(a, b) must return true if a ranks strictly before b.
+pub fn sort_largests_with_heap<T, F: Fn(&T, &T) -> bool>(kcs: &mut [T], K: usize, precedes: F) {
+    // This implementation comes from http://web.archive.org/web/20140807181610/http://fallabs.com/blog-ja/promenade.cgi?id=104.
+    // Strategy:
+    //   Basically, this is an in-place heap sort.
+    //   Retain the heap at the first whose size is at most K for efficiently sorting the K largest counts.
+    let K = K.min(kcs.len());
+    if K == 0 {
+        return;
+    }
+    // First, the only first entry is automatically in the heap.
+    let mut cur = 1;
+    // The first K entries are inserted into the heap.
+    // Any entry in the heap satisfies that the entry is "NOT LARGER" than any descendants of it.
+    // The order in the heap are reversed at the third step.
+    // The heap structure is retained as follows:
+    //   [0] -> [1] -> [3] -> ...
+    //              -> [4] -> ...
+    //       -> [2] -> [5] -> ...
+    //              -> [6] -> ...
+    // Hence, prev(k) = (k - 1)/2, left(k) = 2*k + 1, and right(k) = 2*k + 2.
+    while cur < K {
+        // Insert the cur-th entry into the heap.
+        let mut cidx = cur;
+        while cidx > 0 {
+            let pidx = (cidx - 1) / 2; // prev(cidx)
+            if !precedes(&kcs[pidx], &kcs[cidx]) {
+                break
+            }
+            // Swap the current entry with the parent one, because the current one is smaller.
+            kcs.swap(cidx, pidx);
+            // Perform this recursively at the parent entry.
+            cidx = pidx
+        }
+        cur += 1
+    }
+    // Second, the remain entries are inserted into the heap as keeping the heap size is k.
+    while cur < kcs.len() {
+        // Insert the current entry if it is larger than the smallest one in the heap.
+        if precedes(&kcs[cur], &kcs[0]) {
+            // Procedure A: Insert the current entry into the size K heap.
+            // Swap the current entry and the smallest one in the heap.
+            kcs.swap(0, cur);
+            sift_down(kcs, K, &precedes);
+        }
+        cur += 1;
+    }
+    // Third, the entries in the heap are reversed.
+    // This is achieved by shrinking the heap one by one:
+    //   Taking the largest entry as the current one, insert it to the heap.
+    // Hence, the largest entry is being selected as the current one as pushing down the smaller entries.
+    cur = K - 1;
+    while cur > 0 {
+        // Apply procedure A in the size cur heap.
+        kcs.swap(0, cur);
+        sift_down(kcs, cur, &precedes);
+        cur -= 1;
+    }
+}
+
+// sift_down pushes down the root entry in the size K heap.
+fn sift_down<T, F: Fn(&T, &T) -> bool>(kcs: &mut [T], K: usize, precedes: &F) {
+    let (mut pidx, bot) = (0, K/2);
+    while pidx < bot {
+        // Take the smaller child as the current entry.
+        let mut cidx = 2*pidx + 1; // left(cidx)
+        if cidx < K - 1 && precedes(&kcs[cidx], &kcs[cidx+1]) {
+            cidx += 1;
+        }
+        if precedes(&kcs[cidx], &kcs[pidx]) {
+            break
+        }
+        // Swap the current entry with the selected child, because the current one is larger.
+        kcs.swap(pidx, cidx);
+        // Perform this recursively at the child entry.
+        pidx = cidx;
+    }
+}
+
+/// top_k returns the K highest-ranked (id, score) pairs in the ranking order of ranks_before.
+/// The entries are reordered in place.
+pub fn top_k(entries: &mut [(u32, f32)], K: usize) -> Vec<(u32, f32)> {
+    sort_largests_with_heap(entries, K, ranks_before);
+    entries[..K.min(entries.len())].to_vec()
+}